@@ -0,0 +1,302 @@
+//! Authentication of the requests that compiler workers make to the nexus.
+//!
+//! The nexus and the workers share a secret. Every mutating request carries a
+//! timestamp and an HMAC-SHA256 signature, computed with the secret over the
+//! timestamp, the method, the URL path and the body. The nexus rejects
+//! mutating requests whose signatures don't check out, or whose timestamps are
+//! too far from the current time, which limits how long a captured request
+//! could be replayed. `GET` requests are public and aren't checked.
+//!
+//! Because the path is signed, the nexus must see the same path that the
+//! worker requested: a reverse proxy between them may not rewrite it.
+
+use anyhow::{Context, Result, anyhow, ensure};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{Method, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::{
+    fmt,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The header holding the time at which a request was signed, in seconds
+/// since the Unix epoch.
+pub const TIMESTAMP_HEADER: &str = "x-ttpedia-timestamp";
+
+/// The header holding the hex-encoded signature of a request.
+pub const SIGNATURE_HEADER: &str = "x-ttpedia-signature";
+
+/// How far a request's timestamp may be from the nexus's clock, in seconds.
+pub const MAX_CLOCK_SKEW_SECS: u64 = 300;
+
+/// The minimum length of a shared secret, in bytes.
+pub const MIN_SECRET_LEN: usize = 16;
+
+/// The largest request body accepted on the signed routes. The body has to be
+/// buffered before its signature can be checked, so anything bigger is
+/// rejected without being read. The nexus also uses this as the limit for its
+/// handlers.
+pub const MAX_BODY_LEN: usize = 4 * 1024 * 1024;
+
+/// The secret shared by the nexus and the compiler workers.
+#[derive(Clone)]
+pub struct ServiceKey(Arc<[u8]>);
+
+// Keep the secret out of any debug output.
+impl fmt::Debug for ServiceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ServiceKey(..)")
+    }
+}
+
+impl ServiceKey {
+    /// Create a key from a configured secret.
+    pub fn new(secret: &str) -> Result<Self> {
+        ensure!(
+            secret.len() >= MIN_SECRET_LEN,
+            "the nexus secret must be at least {MIN_SECRET_LEN} bytes long"
+        );
+        Ok(ServiceKey(secret.as_bytes().into()))
+    }
+
+    /// Generate a random key, for when the nexus and the worker run in the
+    /// same process.
+    pub fn generate() -> Result<Self> {
+        let mut secret = [0u8; 32];
+        getrandom::fill(&mut secret).map_err(|e| anyhow!("failed to generate a secret: {e}"))?;
+        Ok(ServiceKey(secret.as_slice().into()))
+    }
+
+    fn mac(&self, timestamp: u64, method: &Method, path: &str, body: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        mac.update(format!("{timestamp}\n{method}\n{path}\n").as_bytes());
+        mac.update(body);
+        mac
+    }
+
+    /// Compute the hex-encoded signature of a request.
+    pub fn sign(&self, timestamp: u64, method: &Method, path: &str, body: &[u8]) -> String {
+        hex::encode(
+            self.mac(timestamp, method, path, body)
+                .finalize()
+                .into_bytes(),
+        )
+    }
+
+    /// Check the signature of a request, in constant time.
+    pub fn verify(
+        &self,
+        timestamp: u64,
+        method: &Method,
+        path: &str,
+        body: &[u8],
+        signature: &str,
+    ) -> Result<()> {
+        let signature = hex::decode(signature).context("malformed signature")?;
+        self.mac(timestamp, method, path, body)
+            .verify_slice(&signature)
+            .map_err(|_| anyhow!("incorrect signature"))
+    }
+
+    /// Build a signed `POST` request with a JSON body.
+    pub fn post_json<T: Serialize>(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        body: &T,
+    ) -> Result<reqwest::RequestBuilder> {
+        let url = reqwest::Url::parse(url).with_context(|| format!("invalid URL `{url}`"))?;
+        let body = serde_json::to_vec(body)?;
+        let timestamp = unix_time();
+        let signature = self.sign(timestamp, &Method::POST, url.path(), &body);
+
+        Ok(client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, signature)
+            .body(body))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before 1970")
+        .as_secs()
+}
+
+/// Middleware that rejects mutating requests that aren't properly signed.
+/// Requests with safe methods pass through untouched.
+pub async fn require_signature(
+    State(key): State<ServiceKey>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+
+    let (parts, body) = req.into_parts();
+
+    let body = match read_body(&parts, body).await {
+        Ok(b) => b,
+        Err(resp) => return resp,
+    };
+
+    if let Err(e) = check_request(&key, &parts, &body) {
+        tracing::warn!(path = %parts.uri.path(), "rejecting unauthenticated request: {e:#}");
+        return (StatusCode::UNAUTHORIZED, format!("{e:#}")).into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Read a request body, giving up as soon as it's known to be too big.
+async fn read_body(parts: &Parts, body: Body) -> Result<Bytes, Response> {
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("request body is larger than {MAX_BODY_LEN} bytes"),
+        )
+            .into_response()
+    };
+
+    let declared_len = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if declared_len.is_some_and(|n| n > MAX_BODY_LEN as u64) {
+        return Err(too_large());
+    }
+
+    let mut stream = body.into_data_stream();
+    let mut buf = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("failed to read request body: {e}"),
+            )
+                .into_response()
+        })?;
+
+        if buf.len() + chunk.len() > MAX_BODY_LEN {
+            return Err(too_large());
+        }
+
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf.into())
+}
+
+fn check_request(key: &ServiceKey, parts: &Parts, body: &[u8]) -> Result<()> {
+    let get_header = |name: &str| -> Result<&str> {
+        parts
+            .headers
+            .get(name)
+            .ok_or_else(|| anyhow!("missing `{name}` header"))?
+            .to_str()
+            .map_err(|_| anyhow!("malformed `{name}` header"))
+    };
+
+    let timestamp: u64 = get_header(TIMESTAMP_HEADER)?
+        .parse()
+        .context("malformed timestamp")?;
+    ensure!(
+        unix_time().abs_diff(timestamp) <= MAX_CLOCK_SKEW_SECS,
+        "request timestamp is too far from the current time"
+    );
+
+    key.verify(
+        timestamp,
+        &parts.method,
+        parts.uri.path(),
+        body,
+        get_header(SIGNATURE_HEADER)?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_verify_1() {
+        let key = ServiceKey::new("correct horse battery staple").unwrap();
+        let path = "/ttpapi1/nexus/pass1";
+        let sig = key.sign(1000, &Method::POST, path, b"{}");
+
+        assert!(key.verify(1000, &Method::POST, path, b"{}", &sig).is_ok());
+        assert!(key.verify(1001, &Method::POST, path, b"{}", &sig).is_err());
+        assert!(key.verify(1000, &Method::PUT, path, b"{}", &sig).is_err());
+        assert!(
+            key.verify(
+                1000,
+                &Method::POST,
+                "/ttpapi1/nexus/rename_entry",
+                b"{}",
+                &sig
+            )
+            .is_err()
+        );
+        assert!(key.verify(1000, &Method::POST, path, b"[]", &sig).is_err());
+        assert!(
+            key.verify(1000, &Method::POST, path, b"{}", "nothex")
+                .is_err()
+        );
+
+        let other = ServiceKey::new("incorrect horse battery staple").unwrap();
+        assert!(
+            other
+                .verify(1000, &Method::POST, path, b"{}", &sig)
+                .is_err()
+        );
+
+        assert!(ServiceKey::new("short").is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_body_1() {
+        use axum::{Router, middleware::from_fn_with_state, routing::post};
+        use tower::ServiceExt;
+
+        let key = ServiceKey::new("correct horse battery staple").unwrap();
+        let app = Router::new()
+            .route("/x", post(|| async { "ok" }))
+            .route_layer(from_fn_with_state(key, require_signature));
+
+        // An endless body: the request could never be answered if the body
+        // were read in full.
+        let chunks = futures::stream::repeat_with(|| {
+            Ok::<_, std::io::Error>(Bytes::from_static(&[0; 65536]))
+        });
+        let req = Request::post("/x").body(Body::from_stream(chunks)).unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = Request::post("/x")
+            .header(header::CONTENT_LENGTH, MAX_BODY_LEN + 1)
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = Request::post("/x").body(Body::from("{}")).unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
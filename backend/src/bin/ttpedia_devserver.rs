@@ -0,0 +1,212 @@
+//! An all-in-one development server.
+//!
+//! This runs the repo server, the nexus, and a compiler worker in a single
+//! process, and serves the compiled outputs out of a local directory. No
+//! Faktory, bucket storage, or reverse proxy is needed. The default listen
+//! address matches the defaults in the frontend's `nuxt.config.ts`, so that
+//! `yarn dev` can be pointed at this server without further configuration.
+//!
+//! The in-process compiler worker reaches the nexus through this server. With
+//! TLS, the server's certificate won't be valid for `localhost`, so `nexus.url`
+//! must be configured with a host name that the certificate covers.
+
+use anyhow::{Result, bail};
+use axum::http::{HeaderValue, Method, header};
+use clap::Parser;
+use samod::{PeerId, Repo, storage::TokioFilesystemStorage};
+use std::{path::PathBuf, sync::Arc};
+use tokio::sync::mpsc;
+use tower_http::{cors::CorsLayer, services::ServeDir, trace::TraceLayer};
+
+use ttpedia_backend::{
+    auth::ServiceKey,
+    config::{ConfigArgs, DEFAULT_DEVSERVER_LISTEN, ServerArgs},
+    logging,
+    monitoring::{self, Component},
+    nexus::{self, NexusState},
+    repo::{self, RepoState},
+    serve::{Shutdown, serve},
+    worker::{self, JobQueue, OutputStore, WorkerConfig},
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    server: ServerArgs,
+
+    /// Directory in which all persistent data are stored.
+    data_root: PathBuf,
+
+    /// The pedia `defs` directory.
+    defs_dir: PathBuf,
+}
+
+impl Args {
+    async fn exec(self) -> Result<()> {
+        logging::init(env!("CARGO_CRATE_NAME"))?;
+
+        let mut config = self.config.load()?;
+        self.server.apply(&mut config.devserver);
+
+        let allowed_origin = config
+            .devserver
+            .allowed_origin
+            .get_or_insert_with(|| "http://localhost:3000".to_owned())
+            .parse::<HeaderValue>()?;
+
+        let port = config
+            .devserver
+            .listen
+            .unwrap_or(DEFAULT_DEVSERVER_LISTEN)
+            .port();
+        let scheme = if config.devserver.tls_paths()?.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+
+        let repo_root = self.data_root.join("repo");
+        let nexus_root = self.data_root.join("nexus");
+        let outputs_root = self.data_root.join("ttpdata");
+
+        for dir in [&repo_root, &nexus_root, &outputs_root] {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // The nexus
+
+        let public_data_url = config
+            .nexus
+            .public_data_url
+            .clone()
+            .unwrap_or_else(|| format!("{scheme}://localhost:{port}/ttpdata"));
+        // The worker and the nexus live in this process, so unless a secret
+        // has been configured, they can just agree on a random one.
+
+        let nexus_key = match config.nexus.secret {
+            Some(_) => config.nexus.service_key()?,
+            None => ServiceKey::generate()?,
+        };
+
+        let nexus_state = NexusState::new(&nexus_root, public_data_url, nexus_key.clone())?;
+        let shutdown = Shutdown::listen();
+
+        // The in-process compiler worker. It talks to the nexus over HTTP,
+        // just like a standalone worker would.
+
+        let nexus_url = match &config.nexus.url {
+            Some(url) => url.clone(),
+            None if scheme == "http" => format!("http://localhost:{port}/ttpapi1/nexus"),
+            None => bail!(
+                "with TLS, `nexus.url` must be configured, with a host name that the certificate is valid for"
+            ),
+        };
+
+        let worker_config = Arc::new(WorkerConfig {
+            defs_dir: self.defs_dir,
+            nexus_url,
+            nexus_key,
+            store: OutputStore::Directory(outputs_root.clone()),
+            html_bucket: config.buckets.html.clone(),
+            shared_assets_bucket: config.buckets.shared_assets.clone(),
+            bundle: config.worker.bundle.clone(),
+            bundle_cache: config.worker.bundle_cache.clone(),
+            only_cached: config.worker.only_cached,
+        });
+
+        let (job_sender, job_receiver) = mpsc::unbounded_channel();
+        let worker_task = tokio::spawn(worker::run_in_process(
+            worker_config,
+            job_receiver,
+            shutdown.clone(),
+        ));
+
+        // The repo server
+
+        let builder = Repo::build_tokio();
+        let storage = TokioFilesystemStorage::new(repo_root);
+        let builder = builder.with_storage(storage);
+        let builder = builder.with_peer_id(PeerId::from_string("ttpedia".to_owned()));
+        let samod = builder.load().await;
+
+        let repo_state = RepoState::new(samod.clone(), JobQueue::InProcess(job_sender));
+
+        // Put it all together. The `/ttpdata/` routes mirror the layout of the
+        // reverse proxy used in the Docker Compose setup.
+
+        let app = axum::Router::new()
+            .merge(repo::router(repo_state.clone()))
+            .merge(nexus::router(nexus_state.clone()))
+            .merge(monitoring::router(vec![
+                Component::Repo(repo_state.clone()),
+                Component::Nexus(nexus_state.clone()),
+            ]))
+            .nest_service(
+                "/ttpdata/html",
+                ServeDir::new(outputs_root.join(&config.buckets.html)),
+            )
+            .nest_service(
+                "/ttpdata/sharedassets",
+                ServeDir::new(outputs_root.join(&config.buckets.shared_assets)),
+            )
+            .layer(
+                CorsLayer::new()
+                    .allow_origin(allowed_origin)
+                    .allow_methods([Method::GET, Method::POST])
+                    .allow_headers([header::CONTENT_TYPE]),
+            )
+            .layer(TraceLayer::new_for_http());
+
+        tracing::info!(peer_id = %samod.peer_id(), "repo loaded");
+
+        // The in-process worker reports to the nexus over HTTP, so the server
+        // has to outlive it. On the signal, the worker stops taking jobs
+        // (after which the repo can't submit any more), and only once any
+        // compilation in progress has finished is the server stopped.
+
+        let (stop_server, server_shutdown) = Shutdown::manual();
+        let server = serve(
+            app,
+            &config.devserver,
+            DEFAULT_DEVSERVER_LISTEN,
+            server_shutdown,
+        );
+        tokio::pin!(server);
+
+        let outcome = tokio::select! {
+            outcome = &mut server => outcome,
+
+            _ = shutdown.wait() => {
+                let worker_outcome = (&mut worker_task).await;
+                let _ = stop_server.send(true);
+                let outcome = server.await;
+                worker_outcome.map_err(anyhow::Error::from).and(outcome)
+            }
+        };
+
+        // Whatever happened, shut down the stateful services so that they're
+        // flushed.
+
+        worker_task.abort();
+        repo_state.stop().await;
+        let closed = nexus_state.close();
+        outcome.and(closed)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    if let Err(err) = args.exec().await {
+        eprintln!("fatal error: {}", err);
+        err.chain()
+            .skip(1)
+            .for_each(|cause| eprintln!("caused by: {}", cause));
+        std::process::exit(1);
+    }
+}
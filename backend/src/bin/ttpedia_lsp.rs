@@ -0,0 +1,804 @@
+//! A Language Server Protocol server for pedia TeX sources.
+//!
+//! The server speaks LSP over standard input and output. It scans open
+//! documents for the index definitions and references that the TeX passes
+//! would record in `pedia.txt`, and checks them against the pedia-wide index
+//! maintained by the nexus. It provides:
+//!
+//! - completion of the names in `\e{…}`, `\explain{…}` and `` \`…` `` term
+//!   references;
+//! - diagnostics for unresolved references and duplicate definitions;
+//! - go-to-definition, leading to the document that defines the target; and
+//! - hovers showing the target's title text.
+//!
+//! Documents in the repo are identified by `automerge:{doc_id}` URIs, which is
+//! what go-to-definition returns for definitions in other documents.
+
+use anyhow::{Result, anyhow};
+use clap::Parser;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tower_lsp::{Client, LanguageServer, LspService, Server, jsonrpc, lsp_types::*};
+
+use ttpedia_backend::{
+    NexusGetBacklinksResponse, NexusGetIndexResponse, NexusIndexEntry,
+    config::ConfigArgs,
+    logging,
+    metadata::{IndexRefFlag, Metadatum, MetadatumBuf, at_unescape},
+};
+
+/// How long the results of looking up an entry in the nexus are reused.
+const LOOKUP_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// The maximum number of completions fetched from the nexus.
+const COMPLETION_LIMIT: usize = 50;
+
+/// The URI scheme of documents in the repo.
+const DOC_URI_SCHEME: &str = "automerge";
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    /// The base URL of the nexus API.
+    #[arg(long, value_name = "URL")]
+    nexus_url: Option<String>,
+}
+
+impl Args {
+    async fn exec(self) -> Result<()> {
+        // Standard output is the LSP channel, so logs go to standard error.
+        logging::init_stderr(env!("CARGO_CRATE_NAME"))?;
+
+        let config = self.config.load()?;
+        let nexus_url = self
+            .nexus_url
+            .or_else(|| config.nexus.url.clone())
+            .ok_or_else(|| anyhow!("no nexus URL has been configured"))?;
+
+        let (service, socket) = LspService::new(|client| PediaLanguageServer {
+            client,
+            nexus: NexusClient::new(nexus_url),
+            documents: Default::default(),
+        });
+
+        Server::new(tokio::io::stdin(), tokio::io::stdout(), socket)
+            .serve(service)
+            .await;
+        Ok(())
+    }
+}
+
+/// An index definition or reference found in a source document.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct SourceItem {
+    /// The byte offset of the start of the entry name in the document.
+    start: usize,
+
+    /// The byte offset of the end of the entry name in the document.
+    end: usize,
+
+    /// What the TeX passes would record for the item. This is an
+    /// [`Metadatum::IndexDef`], [`Metadatum::IndexRef`] or
+    /// [`Metadatum::Alias`].
+    metadatum: MetadatumBuf,
+}
+
+impl SourceItem {
+    /// Get the index and entry name of the item. For an alias, this is the
+    /// alias name.
+    fn key(&self) -> (&str, &str) {
+        match self.metadatum.as_metadatum() {
+            Metadatum::IndexDef { index, entry, .. } | Metadatum::IndexRef { index, entry, .. } => {
+                (index, entry)
+            }
+            Metadatum::Alias { index, alias, .. } => (index, alias),
+            _ => ("", ""),
+        }
+    }
+}
+
+/// Scan TeX source for index definitions and references.
+///
+/// This doesn't expand any macros: it recognizes the commands of the pedia
+/// class that define and reference entries in the `entries`, `explainers` and
+/// `terms` indices, skipping comments and verbatim code.
+fn scan_source(text: &str) -> Vec<SourceItem> {
+    let bytes = text.as_bytes();
+    let mut items = Vec::new();
+    let mut current_entry: Option<String> = None;
+    let mut pos = 0;
+
+    let def = |index: &str, (start, end): (usize, usize)| SourceItem {
+        start,
+        end,
+        metadatum: MetadatumBuf::IndexDef {
+            index: index.to_owned(),
+            entry: text[start..end].to_owned(),
+            fragment: String::new(),
+        },
+    };
+
+    let reference = |index: &str, (start, end): (usize, usize)| SourceItem {
+        start,
+        end,
+        metadatum: MetadatumBuf::IndexRef {
+            index: index.to_owned(),
+            entry: text[start..end].to_owned(),
+            flags: IndexRefFlag::NeedsLoc as u8 | IndexRefFlag::NeedsText as u8,
+        },
+    };
+
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'%' => {
+                pos = text[pos..].find('\n').map_or(text.len(), |i| pos + i + 1);
+                continue;
+            }
+
+            b'\\' => {}
+
+            _ => {
+                pos += 1;
+                continue;
+            }
+        }
+
+        // We're at a control sequence. Control words are runs of letters;
+        // anything else is a control symbol.
+
+        let name_start = pos + 1;
+        let name_end = text[name_start..]
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .map_or(text.len(), |i| name_start + i);
+        let name_end = if name_end == name_start {
+            text[name_start..]
+                .chars()
+                .next()
+                .map_or(name_start, |c| name_start + c.len_utf8())
+        } else {
+            name_end
+        };
+
+        let cseq = &text[name_start..name_end];
+        pos = name_end;
+
+        match cseq {
+            "`" => {
+                if let Some(len) = text[pos..].find('`') {
+                    items.push(reference("terms", (pos, pos + len)));
+                    pos += len + 1;
+                }
+            }
+
+            "tex" => {
+                // `\tex|...|`, with any delimiter.
+                if let Some(delim) = text[pos..].chars().next() {
+                    let body = pos + delim.len_utf8();
+                    pos = text[body..]
+                        .find(delim)
+                        .map_or(text.len(), |i| body + i + delim.len_utf8());
+                }
+            }
+
+            "begin" => {
+                if let Some((start, end)) = braced_arg(text, pos)
+                    && &text[start..end] == "texdisp"
+                {
+                    pos = text[end..]
+                        .find(r"\end{texdisp}")
+                        .map_or(text.len(), |i| end + i);
+                }
+            }
+
+            "Entry" => {
+                if let Some(arg) = braced_arg(text, pos) {
+                    current_entry = Some(text[arg.0..arg.1].to_owned());
+                    items.push(def("entries", arg));
+                    pos = arg.1 + 1;
+                }
+            }
+
+            "Explainer" => {
+                if let Some(arg) = braced_arg(text, pos) {
+                    items.push(def("explainers", arg));
+                    pos = arg.1 + 1;
+                }
+            }
+
+            "DeclareTerm" => {
+                // The starred form gives the TeX and plain forms separately;
+                // the plain one names the term.
+                let starred = text[pos..].starts_with('*');
+
+                if starred {
+                    pos += 1;
+                }
+
+                let mut arg = braced_arg(text, pos);
+
+                if starred {
+                    arg = arg.and_then(|a| braced_arg(text, a.1 + 1));
+                }
+
+                if let Some(arg) = arg {
+                    items.push(def("terms", arg));
+                    pos = arg.1 + 1;
+                }
+            }
+
+            "EntryAlias" => {
+                if let Some((start, end)) = braced_arg(text, pos) {
+                    if let Some(target) = &current_entry {
+                        items.push(SourceItem {
+                            start,
+                            end,
+                            metadatum: MetadatumBuf::Alias {
+                                index: "entries".to_owned(),
+                                alias: text[start..end].to_owned(),
+                                target: target.clone(),
+                            },
+                        });
+                    }
+
+                    pos = end + 1;
+                }
+            }
+
+            "e" | "explain" => {
+                if let Some(arg) = braced_arg(text, pos) {
+                    let index = if cseq == "e" { "entries" } else { "explainers" };
+                    items.push(reference(index, arg));
+                    pos = arg.1 + 1;
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    items
+}
+
+/// Find the braced argument starting at `pos`, after any whitespace. Returns
+/// the byte range of its contents, not including the braces.
+fn braced_arg(text: &str, pos: usize) -> Option<(usize, usize)> {
+    let start = pos + text[pos..].find(|c: char| !c.is_ascii_whitespace())?;
+
+    if !text[start..].starts_with('{') {
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut chars = text[start..].char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+
+                if depth == 0 {
+                    return Some((start + 1, start + i));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Figure out whether the text before `offset` is an unfinished reference,
+/// returning its index and the part of the name that has been typed so far.
+fn completion_context(text: &str, offset: usize) -> Option<(&'static str, &str)> {
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let before = &text[line_start..offset];
+
+    [
+        (r"\e{", "entries", '}'),
+        (r"\explain{", "explainers", '}'),
+        (r"\`", "terms", '`'),
+    ]
+    .into_iter()
+    .filter_map(|(opener, index, closer)| {
+        let start = before.rfind(opener)? + opener.len();
+        let typed = &before[start..];
+        (!typed.contains(closer)).then_some((start, index, typed))
+    })
+    .max_by_key(|(start, _, _)| *start)
+    .map(|(_, index, typed)| (index, typed))
+}
+
+/// Convert a byte offset in `text` into an LSP position, which counts UTF-16
+/// code units.
+fn position_at(text: &str, offset: usize) -> Position {
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character = before[line_start..].encode_utf16().count();
+    Position::new(line as u32, character as u32)
+}
+
+/// Convert an LSP position into a byte offset in `text`, clamping it to the
+/// end of its line or of the text.
+fn offset_at(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+
+    let line = &text[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let mut units = 0;
+
+    for (i, c) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + i;
+        }
+
+        units += c.len_utf16();
+    }
+
+    line_start + line.len()
+}
+
+fn range_of(text: &str, item: &SourceItem) -> Range {
+    Range::new(position_at(text, item.start), position_at(text, item.end))
+}
+
+/// The result of looking up an entry, and when it was fetched.
+type CachedLookup = (Instant, Option<NexusIndexEntry>);
+
+/// A client of the nexus index API, caching its answers for a little while.
+struct NexusClient {
+    http: reqwest::Client,
+    base_url: String,
+    cache: Mutex<HashMap<(String, String), CachedLookup>>,
+}
+
+impl NexusClient {
+    fn new(base_url: String) -> Self {
+        NexusClient {
+            http: reqwest::Client::new(),
+            base_url,
+            cache: Default::default(),
+        }
+    }
+
+    /// Build the URL of a nexus endpoint, escaping the path segments.
+    fn url(&self, segments: &[&str]) -> Result<reqwest::Url> {
+        let mut url = reqwest::Url::parse(&self.base_url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("nexus URL `{}` cannot be a base", self.base_url))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    /// Fetch the entries of an index whose names start with `prefix`.
+    async fn list(&self, index: &str, prefix: &str, limit: usize) -> Result<Vec<NexusIndexEntry>> {
+        let resp: NexusGetIndexResponse = self
+            .http
+            .get(self.url(&["index", index])?)
+            .query(&[("prefix", prefix), ("limit", &limit.to_string())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(resp.entries)
+    }
+
+    /// Look up the definition of an index entry, following aliases. Returns
+    /// `None` if the entry is unknown.
+    async fn lookup(&self, index: &str, entry: &str) -> Result<Option<NexusIndexEntry>> {
+        let key = (index.to_owned(), entry.to_owned());
+
+        if let Some((when, found)) = self.cache.lock().await.get(&key)
+            && when.elapsed() < LOOKUP_CACHE_TTL
+        {
+            return Ok(found.clone());
+        }
+
+        let mut found = self.lookup_def(index, entry).await?;
+
+        if found.is_none() {
+            // The index listing doesn't include aliases, but the backlinks
+            // endpoint tells us the entry that an alias stands for.
+            let resp: NexusGetBacklinksResponse = self
+                .http
+                .get(self.url(&["backlinks", index, entry])?)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            if resp.entry != entry {
+                found = self.lookup_def(index, &resp.entry).await?;
+            }
+        }
+
+        self.cache
+            .lock()
+            .await
+            .insert(key, (Instant::now(), found.clone()));
+        Ok(found)
+    }
+
+    async fn lookup_def(&self, index: &str, entry: &str) -> Result<Option<NexusIndexEntry>> {
+        let entries = self.list(index, entry, 1).await?;
+        Ok(entries.into_iter().find(|e| e.name == entry))
+    }
+}
+
+struct PediaLanguageServer {
+    client: Client,
+    nexus: NexusClient,
+    documents: Mutex<HashMap<Url, String>>,
+}
+
+impl PediaLanguageServer {
+    async fn document(&self, uri: &Url) -> Option<String> {
+        self.documents.lock().await.get(uri).cloned()
+    }
+
+    /// Find the item of a document at a position.
+    async fn item_at(&self, params: &TextDocumentPositionParams) -> Option<(String, SourceItem)> {
+        let text = self.document(&params.text_document.uri).await?;
+        let offset = offset_at(&text, params.position);
+        let item = scan_source(&text)
+            .into_iter()
+            .find(|i| i.start <= offset && offset <= i.end)?;
+        Some((text, item))
+    }
+
+    async fn update_diagnostics(&self, uri: Url, text: &str) {
+        let diagnostics = self.diagnose(&uri, text).await;
+        self.client
+            .publish_diagnostics(uri, diagnostics, None)
+            .await;
+    }
+
+    async fn diagnose(&self, uri: &Url, text: &str) -> Vec<Diagnostic> {
+        let items = scan_source(text);
+        let this_doc_id = (uri.scheme() == DOC_URI_SCHEME).then(|| uri.path());
+        let mut defined = HashSet::new();
+        let mut diagnostics = Vec::new();
+
+        for item in &items {
+            if matches!(item.metadatum, MetadatumBuf::IndexRef { .. }) {
+                continue;
+            }
+
+            let (index, entry) = item.key();
+
+            if !defined.insert((index, entry)) {
+                diagnostics.push(Diagnostic::new_simple(
+                    range_of(text, item),
+                    format!("`{entry}` is already defined in the `{index}` index in this document"),
+                ));
+                continue;
+            }
+
+            // Definitions that the nexus attributes to a different document
+            // are probably duplicates too.
+
+            let Some(this_doc_id) = this_doc_id else {
+                continue;
+            };
+
+            if let Ok(Some(found)) = self.nexus.lookup(index, entry).await
+                && found.name == entry
+                && let Some(doc_id) = found.doc_id
+                && doc_id != this_doc_id
+            {
+                diagnostics.push(Diagnostic {
+                    range: range_of(text, item),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    message: format!(
+                        "`{entry}` is also defined in the `{index}` index by document {doc_id}"
+                    ),
+                    ..Default::default()
+                });
+            }
+        }
+
+        for item in &items {
+            if !matches!(item.metadatum, MetadatumBuf::IndexRef { .. }) {
+                continue;
+            }
+
+            let (index, entry) = item.key();
+
+            if defined.contains(&(index, entry)) {
+                continue;
+            }
+
+            // If the nexus can't be reached, we don't know whether the
+            // reference resolves, so we don't complain.
+
+            match self.nexus.lookup(index, entry).await {
+                Ok(Some(_)) => {}
+                Ok(None) => diagnostics.push(Diagnostic {
+                    range: range_of(text, item),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    message: format!("unresolved reference to `{entry}` in the `{index}` index"),
+                    ..Default::default()
+                }),
+                Err(e) => tracing::warn!("failed to look up `{entry}` in the nexus: {e}"),
+            }
+        }
+
+        diagnostics
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for PediaLanguageServer {
+    async fn initialize(&self, _: InitializeParams) -> jsonrpc::Result<InitializeResult> {
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::FULL,
+                )),
+                completion_provider: Some(CompletionOptions {
+                    trigger_characters: Some(vec!["{".to_owned(), "`".to_owned()]),
+                    ..Default::default()
+                }),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                definition_provider: Some(OneOf::Left(true)),
+                ..Default::default()
+            },
+            server_info: Some(ServerInfo {
+                name: env!("CARGO_CRATE_NAME").to_owned(),
+                version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            }),
+        })
+    }
+
+    async fn shutdown(&self) -> jsonrpc::Result<()> {
+        Ok(())
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let doc = params.text_document;
+        self.documents
+            .lock()
+            .await
+            .insert(doc.uri.clone(), doc.text.clone());
+        self.update_diagnostics(doc.uri, &doc.text).await;
+    }
+
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        // We only ask for full-text synchronization, so the last change holds
+        // the whole document.
+        let Some(change) = params.content_changes.into_iter().last() else {
+            return;
+        };
+
+        let uri = params.text_document.uri;
+        self.documents
+            .lock()
+            .await
+            .insert(uri.clone(), change.text.clone());
+        self.update_diagnostics(uri, &change.text).await;
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+        self.documents.lock().await.remove(&uri);
+        self.client.publish_diagnostics(uri, Vec::new(), None).await;
+    }
+
+    async fn completion(
+        &self,
+        params: CompletionParams,
+    ) -> jsonrpc::Result<Option<CompletionResponse>> {
+        let position = params.text_document_position;
+
+        let Some(text) = self.document(&position.text_document.uri).await else {
+            return Ok(None);
+        };
+
+        let Some((index, typed)) = completion_context(&text, offset_at(&text, position.position))
+        else {
+            return Ok(None);
+        };
+
+        let mut seen = HashSet::new();
+        let mut items = Vec::new();
+
+        // Definitions in this document come first, since they might not have
+        // made it to the nexus yet.
+
+        for item in scan_source(&text) {
+            let (item_index, name) = item.key();
+
+            if matches!(item.metadatum, MetadatumBuf::IndexRef { .. })
+                || item_index != index
+                || !name.starts_with(typed)
+                || !seen.insert(name.to_owned())
+            {
+                continue;
+            }
+
+            items.push(CompletionItem {
+                label: name.to_owned(),
+                kind: Some(CompletionItemKind::REFERENCE),
+                detail: Some("defined in this document".to_owned()),
+                ..Default::default()
+            });
+        }
+
+        match self.nexus.list(index, typed, COMPLETION_LIMIT).await {
+            Ok(entries) => {
+                for entry in entries {
+                    if !seen.insert(entry.name.clone()) {
+                        continue;
+                    }
+
+                    items.push(CompletionItem {
+                        detail: Some(at_unescape(&entry.atplain).into_owned())
+                            .filter(|t| !t.is_empty()),
+                        label: entry.name,
+                        kind: Some(CompletionItemKind::REFERENCE),
+                        ..Default::default()
+                    });
+                }
+            }
+
+            Err(e) => tracing::warn!("failed to list `{index}` completions: {e}"),
+        }
+
+        Ok(Some(CompletionResponse::List(CompletionList {
+            is_incomplete: items.len() >= COMPLETION_LIMIT,
+            items,
+        })))
+    }
+
+    async fn hover(&self, params: HoverParams) -> jsonrpc::Result<Option<Hover>> {
+        let Some((text, item)) = self.item_at(&params.text_document_position_params).await else {
+            return Ok(None);
+        };
+
+        let (index, entry) = item.key();
+
+        let found = match self.nexus.lookup(index, entry).await {
+            Ok(found) => found,
+            Err(e) => {
+                tracing::warn!("failed to look up `{entry}` in the nexus: {e}");
+                None
+            }
+        };
+
+        let contents = match found {
+            Some(found) => {
+                let title = at_unescape(&found.atplain);
+                let title = if title.is_empty() {
+                    found.name.as_str()
+                } else {
+                    &title
+                };
+                format!("**{title}**\n\n`{index}` entry at `{}`", found.location)
+            }
+            None => format!("`{index}` entry `{entry}`, not yet known to the nexus"),
+        };
+
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: contents,
+            }),
+            range: Some(range_of(&text, &item)),
+        }))
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> jsonrpc::Result<Option<GotoDefinitionResponse>> {
+        let position = &params.text_document_position_params;
+
+        let Some((text, item)) = self.item_at(position).await else {
+            return Ok(None);
+        };
+
+        let (index, entry) = item.key();
+
+        // Prefer a definition in the same document.
+
+        if let Some(def) = scan_source(&text).into_iter().find(|i| {
+            !matches!(i.metadatum, MetadatumBuf::IndexRef { .. }) && i.key() == (index, entry)
+        }) {
+            return Ok(Some(GotoDefinitionResponse::Scalar(Location::new(
+                position.text_document.uri.clone(),
+                range_of(&text, &def),
+            ))));
+        }
+
+        let doc_id = match self.nexus.lookup(index, entry).await {
+            Ok(found) => found.and_then(|f| f.doc_id),
+            Err(e) => {
+                tracing::warn!("failed to look up `{entry}` in the nexus: {e}");
+                None
+            }
+        };
+
+        let Some(uri) = doc_id.and_then(|d| Url::parse(&format!("{DOC_URI_SCHEME}:{d}")).ok())
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(GotoDefinitionResponse::Scalar(Location::new(
+            uri,
+            Range::default(),
+        ))))
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    if let Err(err) = args.exec().await {
+        eprintln!("fatal error: {}", err);
+        err.chain()
+            .skip(1)
+            .for_each(|cause| eprintln!("caused by: {}", cause));
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_1() {
+        let text = "\\Entry{dump}{\\dump}{@Bdump}\n\\EntryAlias{dumping}\n\
+            See \\e{end} and \\`format`. % \\e{commented}\n\
+            \\tex|\\e{verbatim}| \\DeclareTerm*{\\TeX}{TeX}\n";
+        let items = scan_source(text);
+        let keys: Vec<_> = items.iter().map(|i| i.key()).collect();
+        assert_eq!(
+            keys,
+            [
+                ("entries", "dump"),
+                ("entries", "dumping"),
+                ("entries", "end"),
+                ("terms", "format"),
+                ("terms", "TeX"),
+            ]
+        );
+        assert!(matches!(items[2].metadatum, MetadatumBuf::IndexRef { .. }));
+        assert_eq!(&text[items[2].start..items[2].end], "end");
+    }
+
+    #[test]
+    fn positions_1() {
+        let text = "ab\n\u{1d54f}x\\e{";
+        assert_eq!(position_at(text, 8), Position::new(1, 3));
+        assert_eq!(offset_at(text, Position::new(1, 3)), 8);
+        assert_eq!(offset_at(text, Position::new(0, 99)), 2);
+        assert_eq!(completion_context(text, text.len()), Some(("entries", "")));
+        assert_eq!(
+            completion_context("\\e{do} \\`fo", 11),
+            Some(("terms", "fo"))
+        );
+        assert_eq!(completion_context("\\e{done}", 8), None);
+    }
+}
@@ -1,12 +1,17 @@
 //! Miscellaneous utilities for ttpedia.
 
-use anyhow::Result;
+use anyhow::{Context, Result, anyhow, bail};
 use automerge::{Automerge, ObjType, ROOT, transaction::Transactable};
 use clap::Parser;
 use minio::s3::types::S3Api;
 use samod::{Repo, storage::TokioFilesystemStorage};
+use serde::Serialize;
 use std::path::PathBuf;
 
+use ttpedia_backend::{
+    NexusPostRenameEntryRequest, NexusPostRenameEntryResponse, config::ConfigArgs, logging,
+};
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -14,6 +19,13 @@ struct Args {
     cmd: Subcommands,
 }
 
+impl Args {
+    async fn exec(self) -> Result<()> {
+        logging::init(env!("CARGO_CRATE_NAME"))?;
+        self.cmd.exec().await
+    }
+}
+
 #[derive(Parser, Debug)]
 enum Subcommands {
     /// Import a file into an on-disk repo.
@@ -21,6 +33,9 @@ enum Subcommands {
 
     /// Create a bucket in a bucket storage service.
     MakeBucket(MakeBucketCommand),
+
+    /// Rename an entry, redirecting its old name to the new one.
+    RenameEntry(RenameEntryCommand),
 }
 
 impl Subcommands {
@@ -28,6 +43,7 @@ impl Subcommands {
         match self {
             Subcommands::Import(a) => a.exec().await,
             Subcommands::MakeBucket(a) => a.exec().await,
+            Subcommands::RenameEntry(a) => a.exec().await,
         }
     }
 }
@@ -71,6 +87,9 @@ impl ImportCommand {
 #[derive(Parser, Debug)]
 #[command()]
 struct MakeBucketCommand {
+    #[command(flatten)]
+    config: ConfigArgs,
+
     #[arg(long)]
     public: bool,
 
@@ -86,8 +105,15 @@ struct MakeBucketCommand {
 
 impl MakeBucketCommand {
     async fn exec(self) -> Result<()> {
-        let bucket_username = std::env::var("TTPEDIA_BUCKET_USERNAME")?;
-        let bucket_password = std::env::var("TTPEDIA_BUCKET_PASSWORD")?;
+        let config = self.config.load()?;
+        let bucket_username = config
+            .buckets
+            .username
+            .ok_or_else(|| anyhow!("no bucket storage username has been configured"))?;
+        let bucket_password = config
+            .buckets
+            .password
+            .ok_or_else(|| anyhow!("no bucket storage password has been configured"))?;
 
         let base_url: minio::s3::http::BaseUrl = self.url.parse()?;
         let provider =
@@ -137,14 +163,97 @@ impl MakeBucketCommand {
     }
 }
 
+#[derive(Parser, Debug)]
+#[command()]
+struct RenameEntryCommand {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    /// The base URL of the nexus API.
+    #[arg(long, value_name = "URL")]
+    nexus_url: Option<String>,
+
+    /// Resubmit the documents that reference the entry to the repo server with
+    /// this base URL, so that they're recompiled to use the new name.
+    #[arg(long, value_name = "URL")]
+    recompile_via: Option<String>,
+
+    /// The current name of the entry.
+    #[arg()]
+    old: String,
+
+    /// The new name of the entry, which must already have been defined.
+    #[arg()]
+    new: String,
+}
+
+/// The request to the repo server's `POST /submit` endpoint.
+#[derive(Serialize)]
+struct RepoSubmitRequest<'a> {
+    doc_id: &'a str,
+}
+
+impl RenameEntryCommand {
+    async fn exec(self) -> Result<()> {
+        let config = self.config.load()?;
+        let nexus_url = self
+            .nexus_url
+            .or_else(|| config.nexus.url.clone())
+            .ok_or_else(|| anyhow!("no nexus URL has been configured"))?;
+
+        let nexus_key = config.nexus.service_key()?;
+
+        let client = reqwest::Client::new();
+        let resp: NexusPostRenameEntryResponse = nexus_key
+            .post_json(
+                &client,
+                &format!("{nexus_url}/rename_entry"),
+                &NexusPostRenameEntryRequest {
+                    old: self.old.clone(),
+                    new: self.new.clone(),
+                },
+            )?
+            .send()
+            .await
+            .context("HTTP rename to nexus didnt send")?
+            .error_for_status()
+            .context("HTTP rename to nexus failed")?
+            .json()
+            .await
+            .context("HTTP rename resp json")?;
+
+        if resp.status != "ok" {
+            bail!("nexus refused to rename the entry: {}", resp.status);
+        }
+
+        println!("Renamed entry `{}` to `{}`", self.old, self.new);
+
+        for doc_id in &resp.referencing_doc_ids {
+            let Some(repo_url) = &self.recompile_via else {
+                println!("Referenced by document {doc_id}");
+                continue;
+            };
+
+            client
+                .post(format!("{repo_url}/submit"))
+                .json(&RepoSubmitRequest { doc_id })
+                .send()
+                .await
+                .context("HTTP submit to repo didnt send")?
+                .error_for_status()
+                .context("HTTP submit to repo failed")?;
+            println!("Resubmitted referencing document {doc_id}");
+        }
+
+        Ok(())
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    //tracing_subscriber::fmt::init();
-    //console_subscriber::init();
-
-    if let Err(err) = args.cmd.exec().await {
+    if let Err(err) = args.exec().await {
         eprintln!("fatal error: {}", err);
         err.chain()
             .skip(1)
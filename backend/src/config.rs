@@ -0,0 +1,498 @@
+//! Runtime configuration shared by the Tectonopedia servers.
+//!
+//! Settings are merged from three layers, each overriding the last: a TOML
+//! file, `TTPEDIA_*` environment variables, and command-line flags. The file
+//! is optional; it is specified with a `--config` flag or the `TTPEDIA_CONFIG`
+//! environment variable. An example:
+//!
+//! ```toml
+//! [repo]
+//! listen = "0.0.0.0:29180"
+//! allowed_origin = "http://localhost:29080"
+//!
+//! [nexus]
+//! listen = "0.0.0.0:29280"
+//! allowed_origin = "http://localhost:29080"
+//! public_data_url = "http://localhost:29180/ttpdata"
+//! url = "http://nexus_server:29280/ttpapi1/nexus"
+//!
+//! [buckets]
+//! url = "http://bucket:9000/"
+//! username = "tectonopedia"
+//! html = "ttpedia-html"
+//! shared_assets = "ttpedia-sharedassets"
+//! ```
+//!
+//! Any server section may also specify `tls_cert` and `tls_key` paths to serve
+//! HTTPS and WSS directly.
+//!
+//! The nexus and the compiler workers also need a shared `secret` in the
+//! `[nexus]` section, used to authenticate the workers' requests. It's best
+//! provided through the `TTPEDIA_NEXUS_SECRET` environment variable rather
+//! than the file.
+//!
+//! By default, the compiler workers download TeX support files from Tectonic's
+//! standard bundle server. For offline use, the `[worker]` section can specify
+//! a local `bundle` (a directory, or a Zip or TTB file), or a `bundle_cache`
+//! directory seeded ahead of time together with `only_cached = true`.
+
+use anyhow::{Context, Result, anyhow, bail};
+use axum::http::HeaderValue;
+use serde::Deserialize;
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+};
+
+use crate::auth::ServiceKey;
+
+/// The default listen address of the repo server.
+pub const DEFAULT_REPO_LISTEN: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 29180));
+
+/// The default listen address of the nexus server.
+pub const DEFAULT_NEXUS_LISTEN: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 29280));
+
+/// The default listen address of the compiler worker's metrics server.
+pub const DEFAULT_WORKER_LISTEN: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 29380));
+
+/// The default listen address of the all-in-one development server. This
+/// matches the defaults in the frontend's `nuxt.config.ts`.
+pub const DEFAULT_DEVSERVER_LISTEN: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 29100));
+
+/// The complete Tectonopedia configuration.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    /// Settings for the repo server.
+    pub repo: ServerConfig,
+
+    /// Settings for the nexus server, including how others find it.
+    pub nexus: NexusConfig,
+
+    /// Settings for the compiler worker, including its health and metrics
+    /// server.
+    pub worker: WorkerSettings,
+
+    /// Settings for the all-in-one development server.
+    pub devserver: ServerConfig,
+
+    /// Settings for the bucket storage service.
+    pub buckets: BucketsConfig,
+}
+
+/// Settings common to all of the HTTP servers.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// The socket address on which to listen. If unspecified, a per-server
+    /// default is used.
+    pub listen: Option<SocketAddr>,
+
+    /// The origin allowed to make cross-origin requests to the server.
+    pub allowed_origin: Option<String>,
+
+    /// Path to a PEM-format TLS certificate chain. If this and `tls_key` are
+    /// provided, the server speaks HTTPS and WSS directly.
+    pub tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM-format private key associated with `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+}
+
+/// Settings specific to the nexus server.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct NexusConfig {
+    /// The generic server settings.
+    #[serde(flatten)]
+    pub server: ServerConfig,
+
+    /// The base URL at which clients can access the published data buckets.
+    pub public_data_url: Option<String>,
+
+    /// The base URL of the nexus API, as used by the compiler workers.
+    pub url: Option<String>,
+
+    /// The secret shared by the nexus and the compiler workers, used to
+    /// authenticate the workers' requests.
+    pub secret: Option<String>,
+}
+
+impl NexusConfig {
+    /// Get the key for authenticating requests to the nexus, which must be
+    /// configured.
+    pub fn service_key(&self) -> Result<ServiceKey> {
+        let secret = self
+            .secret
+            .as_deref()
+            .ok_or_else(|| anyhow!("no nexus secret has been configured"))?;
+        ServiceKey::new(secret)
+    }
+}
+
+/// Settings specific to the compiler worker.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct WorkerSettings {
+    /// The health and metrics server settings.
+    #[serde(flatten)]
+    pub server: ServerConfig,
+
+    /// The TeX support bundle: a directory, a Zip or TTB file, or a URL. If
+    /// unspecified, Tectonic's default bundle is used.
+    pub bundle: Option<String>,
+
+    /// The directory in which network bundles are cached. If unspecified,
+    /// Tectonic's default cache location is used.
+    pub bundle_cache: Option<PathBuf>,
+
+    /// If true, network bundles are never contacted: all files must already
+    /// be in the cache.
+    pub only_cached: bool,
+}
+
+/// Settings for the bucket storage service.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BucketsConfig {
+    /// The base URL of the S3-compatible storage service.
+    pub url: Option<String>,
+
+    /// The username for authenticating with the storage service.
+    pub username: Option<String>,
+
+    /// The password for authenticating with the storage service.
+    pub password: Option<String>,
+
+    /// The name of the bucket holding compiled HTML.
+    pub html: String,
+
+    /// The name of the bucket holding shared assets such as fonts and CSS.
+    pub shared_assets: String,
+}
+
+impl Default for BucketsConfig {
+    fn default() -> Self {
+        BucketsConfig {
+            url: None,
+            username: None,
+            password: None,
+            html: "ttpedia-html".to_owned(),
+            shared_assets: "ttpedia-sharedassets".to_owned(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from the file at `path`, if provided, falling
+    /// back to the `TTPEDIA_CONFIG` environment variable. Environment
+    /// variables are then applied on top of the file's settings.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let env_path = std::env::var_os("TTPEDIA_CONFIG").map(PathBuf::from);
+
+        let mut config = match path.or(env_path.as_deref()) {
+            Some(p) => {
+                let text = std::fs::read_to_string(p)
+                    .with_context(|| format!("failed to read config file `{}`", p.display()))?;
+                Self::from_toml(&text)
+                    .with_context(|| format!("failed to parse config file `{}`", p.display()))?
+            }
+
+            None => Config::default(),
+        };
+
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+
+    /// Parse a configuration from TOML text.
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Apply settings from environment variables, as looked up by `getenv`.
+    fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, getenv: F) -> Result<()> {
+        self.repo.apply_env("TTPEDIA_REPO", &getenv)?;
+        self.nexus.server.apply_env("TTPEDIA_NEXUS", &getenv)?;
+        self.worker.server.apply_env("TTPEDIA_WORKER", &getenv)?;
+        self.devserver.apply_env("TTPEDIA_DEVSERVER", &getenv)?;
+
+        override_from_env(
+            &mut self.nexus.public_data_url,
+            "TTPEDIA_PUBLIC_DATA_URL",
+            &getenv,
+        );
+        override_from_env(&mut self.nexus.url, "TTPEDIA_NEXUS_URL", &getenv);
+        override_from_env(&mut self.nexus.secret, "TTPEDIA_NEXUS_SECRET", &getenv);
+
+        override_from_env(&mut self.worker.bundle, "TTPEDIA_BUNDLE", &getenv);
+        override_from_env(
+            &mut self.worker.bundle_cache,
+            "TTPEDIA_BUNDLE_CACHE",
+            &getenv,
+        );
+
+        if let Some(v) = getenv("TTPEDIA_BUNDLE_ONLY_CACHED") {
+            self.worker.only_cached = v
+                .parse()
+                .context("invalid boolean in $TTPEDIA_BUNDLE_ONLY_CACHED")?;
+        }
+
+        override_from_env(&mut self.buckets.url, "TTPEDIA_BUCKET_URL", &getenv);
+        override_from_env(
+            &mut self.buckets.username,
+            "TTPEDIA_BUCKET_USERNAME",
+            &getenv,
+        );
+        override_from_env(
+            &mut self.buckets.password,
+            "TTPEDIA_BUCKET_PASSWORD",
+            &getenv,
+        );
+
+        if let Some(v) = getenv("TTPEDIA_HTML_BUCKET") {
+            self.buckets.html = v;
+        }
+
+        if let Some(v) = getenv("TTPEDIA_SHARED_ASSETS_BUCKET") {
+            self.buckets.shared_assets = v;
+        }
+
+        Ok(())
+    }
+}
+
+fn override_from_env<T: From<String>, F: Fn(&str) -> Option<String>>(
+    slot: &mut Option<T>,
+    name: &str,
+    getenv: &F,
+) {
+    if let Some(v) = getenv(name) {
+        *slot = Some(v.into());
+    }
+}
+
+impl ServerConfig {
+    fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, prefix: &str, getenv: &F) -> Result<()> {
+        let listen_var = format!("{prefix}_LISTEN");
+
+        if let Some(v) = getenv(&listen_var) {
+            self.listen = Some(
+                v.parse()
+                    .with_context(|| format!("invalid socket address in ${listen_var}"))?,
+            );
+        }
+
+        override_from_env(
+            &mut self.allowed_origin,
+            &format!("{prefix}_ALLOWED_ORIGIN"),
+            getenv,
+        );
+        override_from_env(&mut self.tls_cert, &format!("{prefix}_TLS_CERT"), getenv);
+        override_from_env(&mut self.tls_key, &format!("{prefix}_TLS_KEY"), getenv);
+        Ok(())
+    }
+
+    /// Get the configured allowed origin as an HTTP header value.
+    pub fn allowed_origin_header(&self) -> Result<HeaderValue> {
+        let origin = self
+            .allowed_origin
+            .as_ref()
+            .ok_or_else(|| anyhow!("no allowed origin has been configured"))?;
+        Ok(origin.parse()?)
+    }
+
+    /// Get the TLS certificate and key paths, if TLS has been configured.
+    pub fn tls_paths(&self) -> Result<Option<(&Path, &Path)>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            _ => bail!("TLS requires both a certificate and a key to be configured"),
+        }
+    }
+}
+
+impl BucketsConfig {
+    /// Get the storage service URL and credentials, which must all be
+    /// configured.
+    pub fn credentials(&self) -> Result<(&str, &str, &str)> {
+        let url = self
+            .url
+            .as_deref()
+            .ok_or_else(|| anyhow!("no bucket storage URL has been configured"))?;
+        let username = self
+            .username
+            .as_deref()
+            .ok_or_else(|| anyhow!("no bucket storage username has been configured"))?;
+        let password = self
+            .password
+            .as_deref()
+            .ok_or_else(|| anyhow!("no bucket storage password has been configured"))?;
+        Ok((url, username, password))
+    }
+}
+
+/// Command-line flag for locating the configuration file.
+#[derive(clap::Args, Debug)]
+pub struct ConfigArgs {
+    /// Path to a TOML configuration file.
+    #[arg(long = "config", value_name = "PATH")]
+    pub path: Option<PathBuf>,
+}
+
+impl ConfigArgs {
+    /// Load the configuration, honoring the file flag.
+    pub fn load(&self) -> Result<Config> {
+        Config::load(self.path.as_deref())
+    }
+}
+
+/// Command-line flags that override a [`ServerConfig`].
+#[derive(clap::Args, Debug)]
+pub struct ServerArgs {
+    /// The socket address on which to listen.
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Option<SocketAddr>,
+
+    /// The origin allowed to make cross-origin requests.
+    #[arg(long, value_name = "ORIGIN")]
+    pub allowed_origin: Option<String>,
+
+    /// Path to a PEM-format TLS certificate chain, to serve HTTPS directly.
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM-format private key for `--tls-cert`.
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    /// Apply these flags on top of `config`.
+    pub fn apply(self, config: &mut ServerConfig) {
+        if self.listen.is_some() {
+            config.listen = self.listen;
+        }
+
+        if self.allowed_origin.is_some() {
+            config.allowed_origin = self.allowed_origin;
+        }
+
+        if self.tls_cert.is_some() {
+            config.tls_cert = self.tls_cert;
+        }
+
+        if self.tls_key.is_some() {
+            config.tls_key = self.tls_key;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn layering() {
+        let mut config = Config::from_toml(
+            r#"
+            [repo]
+            listen = "127.0.0.1:1234"
+            allowed_origin = "http://file.example"
+
+            [nexus]
+            listen = "127.0.0.1:2345"
+            public_data_url = "http://data.example"
+
+            [worker]
+            listen = "127.0.0.1:3456"
+            bundle = "/bundles/tlextras.zip"
+
+            [buckets]
+            html = "myhtml"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.repo.listen, Some("127.0.0.1:1234".parse().unwrap()));
+        assert_eq!(
+            config.nexus.server.listen,
+            Some("127.0.0.1:2345".parse().unwrap())
+        );
+        assert_eq!(
+            config.worker.server.listen,
+            Some("127.0.0.1:3456".parse().unwrap())
+        );
+        assert_eq!(
+            config.worker.bundle.as_deref(),
+            Some("/bundles/tlextras.zip")
+        );
+        assert!(!config.worker.only_cached);
+        assert_eq!(config.devserver, ServerConfig::default());
+        assert_eq!(config.buckets.html, "myhtml");
+        assert_eq!(config.buckets.shared_assets, "ttpedia-sharedassets");
+
+        let env: HashMap<&str, &str> = [
+            ("TTPEDIA_REPO_ALLOWED_ORIGIN", "http://env.example"),
+            ("TTPEDIA_NEXUS_TLS_CERT", "/cert.pem"),
+            ("TTPEDIA_BUCKET_URL", "http://bucket.example"),
+            ("TTPEDIA_NEXUS_SECRET", "0123456789abcdef"),
+            ("TTPEDIA_BUNDLE_CACHE", "/cache"),
+            ("TTPEDIA_BUNDLE_ONLY_CACHED", "true"),
+        ]
+        .into_iter()
+        .collect();
+
+        config
+            .apply_env(|name| env.get(name).map(|v| v.to_string()))
+            .unwrap();
+
+        assert_eq!(config.repo.listen, Some("127.0.0.1:1234".parse().unwrap()));
+        assert_eq!(
+            config.repo.allowed_origin.as_deref(),
+            Some("http://env.example")
+        );
+        assert_eq!(
+            config.nexus.public_data_url.as_deref(),
+            Some("http://data.example")
+        );
+        assert!(config.nexus.server.tls_paths().is_err());
+        assert!(config.nexus.service_key().is_ok());
+        assert!(config.buckets.credentials().is_err());
+        assert_eq!(
+            config.worker.bundle.as_deref(),
+            Some("/bundles/tlextras.zip")
+        );
+        assert_eq!(config.worker.bundle_cache, Some(PathBuf::from("/cache")));
+        assert!(config.worker.only_cached);
+
+        ServerArgs {
+            listen: Some("0.0.0.0:80".parse().unwrap()),
+            allowed_origin: None,
+            tls_cert: None,
+            tls_key: None,
+        }
+        .apply(&mut config.repo);
+
+        assert_eq!(config.repo.listen, Some("0.0.0.0:80".parse().unwrap()));
+        assert_eq!(
+            config.repo.allowed_origin.as_deref(),
+            Some("http://env.example")
+        );
+    }
+
+    #[test]
+    fn bad_env() {
+        let mut config = Config::default();
+        assert!(
+            config
+                .apply_env(|name| (name == "TTPEDIA_NEXUS_LISTEN").then(|| "nope".to_owned()))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,519 @@
+//! Post-processing of the HTML outputs of pass 2 before they're published.
+//!
+//! Document content is user-editable TeX, and the Tectonic HTML engine emits
+//! whatever elements and attributes the `tdux:` specials ask for, while the
+//! frontend injects the published HTML straight into its pages. So before
+//! upload, the outputs are rewritten to remove scripts, event handler
+//! attributes and dangerous URLs. Links to external sites get
+//! `rel="noopener"`, and links to other entry pages are rewritten into
+//! frontend routes.
+//!
+//! Like [`crate::search::html_to_text`], this is not a full HTML parser.
+//! Instead, every tag is parsed into its name and attributes and then rebuilt
+//! from them, with the attribute values re-escaped, so that browsers can't
+//! interpret the output differently than we did.
+
+use crate::{
+    nexus::{entry_name_for_output, output_site_path},
+    search::decode_reference,
+};
+
+/// Elements that are removed along with their contents. Many of these have
+/// contents that browsers don't parse as markup.
+const DROPPED_ELEMENTS: &[&str] = &[
+    "applet",
+    "frameset",
+    "iframe",
+    "noembed",
+    "noframes",
+    "noscript",
+    "object",
+    "plaintext",
+    "script",
+    "style",
+    "template",
+    "textarea",
+    "title",
+    "xmp",
+];
+
+/// Elements whose tags are removed, but whose contents are kept.
+const DROPPED_TAGS: &[&str] = &[
+    "animate",
+    "animatemotion",
+    "animatetransform",
+    "base",
+    "embed",
+    "form",
+    "frame",
+    "link",
+    "meta",
+    "set",
+];
+
+/// Attributes that are always removed, in addition to event handlers.
+const DROPPED_ATTRIBUTES: &[&str] = &["srcdoc", "srcset"];
+
+/// Attributes whose values are URLs.
+const URL_ATTRIBUTES: &[&str] = &[
+    "action",
+    "background",
+    "cite",
+    "codebase",
+    "data",
+    "formaction",
+    "href",
+    "longdesc",
+    "poster",
+    "src",
+    "xlink:href",
+];
+
+/// The URL schemes that links and resources may use. URLs without a scheme
+/// are relative, and always allowed.
+const SAFE_URL_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Post-process an HTML output so that it's safe to publish.
+pub fn postprocess(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(lt) = rest.find('<') {
+        out.push_str(&rest[..lt]);
+        rest = &rest[lt..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |i| &comment[i + 3..]);
+            continue;
+        }
+
+        // Doctypes, CDATA sections and processing instructions.
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            rest = rest.find('>').map_or("", |i| &rest[i + 1..]);
+            continue;
+        }
+
+        let Some((tag, len)) = parse_tag(&rest[1..]) else {
+            // Not a tag, so the `<` is text.
+            out.push_str("&lt;");
+            rest = &rest[1..];
+            continue;
+        };
+
+        rest = &rest[1 + len..];
+
+        // Browsers ignore tags that are cut off by the end of the document.
+        if !tag.terminated {
+            break;
+        }
+
+        let name = tag.name.to_ascii_lowercase();
+
+        if DROPPED_ELEMENTS.contains(&name.as_str()) {
+            // Note that `<script/>` does not close itself.
+            if !tag.closing {
+                let close = format!("</{name}");
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(i) => rest[i..].find('>').map_or("", |j| &rest[i + j + 1..]),
+                    None => "",
+                };
+            }
+
+            continue;
+        }
+
+        if DROPPED_TAGS.contains(&name.as_str()) || !is_valid_name(tag.name) {
+            continue;
+        }
+
+        out.push('<');
+
+        if tag.closing {
+            out.push('/');
+            out.push_str(tag.name);
+            out.push('>');
+            continue;
+        }
+
+        out.push_str(tag.name);
+
+        for (attr, value) in sanitize_attributes(&name, tag.attributes) {
+            out.push(' ');
+            out.push_str(&attr);
+
+            if let Some(value) = value {
+                out.push_str("=\"");
+                push_escaped(&mut out, &value);
+                out.push('"');
+            }
+        }
+
+        if tag.self_closing {
+            out.push_str(" /");
+        }
+
+        out.push('>');
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// A tag parsed from HTML.
+#[derive(Debug)]
+struct Tag<'a> {
+    name: &'a str,
+    closing: bool,
+    self_closing: bool,
+
+    /// Whether the tag ended with a `>`, rather than the end of the text.
+    terminated: bool,
+
+    /// The tag's attributes, with their values decoded.
+    attributes: Vec<(&'a str, Option<String>)>,
+}
+
+/// Parse a tag, given the text following its `<`. Returns the tag and the
+/// number of bytes that it occupies, or `None` if the text doesn't start a
+/// tag.
+///
+/// This follows the tokenization rules of the HTML standard, without the
+/// error recovery that doesn't matter for our purposes.
+fn parse_tag(s: &str) -> Option<(Tag<'_>, usize)> {
+    let (closing, mut pos) = match s.strip_prefix('/') {
+        Some(_) => (true, 1),
+        None => (false, 0),
+    };
+
+    if !s[pos..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let name_len = s[pos..]
+        .find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>')
+        .unwrap_or(s.len() - pos);
+
+    let mut tag = Tag {
+        name: &s[pos..pos + name_len],
+        closing,
+        self_closing: false,
+        terminated: false,
+        attributes: Vec::new(),
+    };
+
+    pos += name_len;
+
+    loop {
+        let rest = &s[pos..];
+
+        let Some(c) = rest.chars().next() else {
+            return Some((tag, pos));
+        };
+
+        match c {
+            '>' => {
+                tag.terminated = true;
+                return Some((tag, pos + 1));
+            }
+
+            // A slash only makes a tag self-closing if it's right before the
+            // `>`; anywhere else, it's ignored.
+            '/' => {
+                tag.self_closing = true;
+                pos += 1;
+                continue;
+            }
+
+            c if c.is_ascii_whitespace() => pos += 1,
+
+            _ => {
+                // An `=` can start an attribute name, but not continue one.
+                let attr_len = rest[1..]
+                    .find(|c: char| c.is_ascii_whitespace() || matches!(c, '/' | '>' | '='))
+                    .map_or(rest.len(), |i| i + 1);
+                let attr = &rest[..attr_len];
+                pos += attr_len;
+
+                let after = s[pos..].trim_start_matches(|c: char| c.is_ascii_whitespace());
+
+                let value = match after.strip_prefix('=') {
+                    Some(v) => {
+                        let v = v.trim_start_matches(|c: char| c.is_ascii_whitespace());
+
+                        let (raw, len) = match v.chars().next() {
+                            Some(q @ ('"' | '\'')) => match v[1..].find(q) {
+                                Some(i) => (&v[1..1 + i], i + 2),
+                                None => (&v[1..], v.len()),
+                            },
+
+                            _ => {
+                                let len = v
+                                    .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                                    .unwrap_or(v.len());
+                                (&v[..len], len)
+                            }
+                        };
+
+                        pos = s.len() - v.len() + len;
+                        Some(decode_attribute(raw))
+                    }
+
+                    None => None,
+                };
+
+                tag.attributes.push((attr, value));
+            }
+        }
+
+        tag.self_closing = false;
+    }
+}
+
+/// Filter and rewrite the attributes of an element.
+fn sanitize_attributes(
+    element: &str,
+    attrs: Vec<(&str, Option<String>)>,
+) -> Vec<(String, Option<String>)> {
+    let mut result: Vec<(String, Option<String>)> = Vec::with_capacity(attrs.len());
+    let mut external_link = false;
+
+    for (attr, mut value) in attrs {
+        let name = attr.to_ascii_lowercase();
+
+        if !is_valid_name(attr)
+            || name.starts_with("on")
+            || DROPPED_ATTRIBUTES.contains(&name.as_str())
+        {
+            continue;
+        }
+
+        if let Some(v) = &mut value {
+            if URL_ATTRIBUTES.contains(&name.as_str()) {
+                let scheme = url_scheme(v);
+
+                if scheme
+                    .as_deref()
+                    .is_some_and(|s| !SAFE_URL_SCHEMES.contains(&s))
+                {
+                    continue;
+                }
+
+                if element == "a" && name == "href" {
+                    external_link = is_external_url(v);
+
+                    if let Some(route) = internal_link_route(v) {
+                        *v = route;
+                    }
+                }
+            } else if name == "style" && !is_safe_style(v) {
+                continue;
+            }
+        }
+
+        result.push((attr.to_owned(), value));
+    }
+
+    if external_link {
+        match result
+            .iter_mut()
+            .find(|(a, _)| a.eq_ignore_ascii_case("rel"))
+        {
+            Some((_, value)) => {
+                let value = value.get_or_insert_default();
+
+                if !value
+                    .split_ascii_whitespace()
+                    .any(|t| t.eq_ignore_ascii_case("noopener"))
+                {
+                    if !value.trim().is_empty() {
+                        value.push(' ');
+                    }
+
+                    value.push_str("noopener");
+                }
+            }
+
+            None => result.push(("rel".to_owned(), Some("noopener".to_owned()))),
+        }
+    }
+
+    result
+}
+
+/// Check whether an element or attribute name is one that we're willing to
+/// pass through. This rules out names that are only produced by malformed
+/// markup.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+}
+
+/// Clean up a URL the way that browsers do before interpreting it, removing
+/// tabs and newlines, and surrounding spaces and control characters.
+fn clean_url(url: &str) -> String {
+    let url: String = url
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    url.trim_matches(|c: char| c <= ' ').to_owned()
+}
+
+/// Get the lowercased scheme of a URL, if it's absolute.
+fn url_scheme(url: &str) -> Option<String> {
+    let url = clean_url(url);
+    let end = url.find([':', '/', '?', '#'])?;
+    url[end..]
+        .starts_with(':')
+        .then(|| url[..end].to_ascii_lowercase())
+}
+
+/// Check whether a URL points to another site: either it's an absolute web
+/// URL, or it's scheme-relative, like `//example.com/`. Browsers treat
+/// backslashes like slashes here.
+fn is_external_url(url: &str) -> bool {
+    let url = clean_url(url);
+
+    matches!(url_scheme(&url).as_deref(), Some("http" | "https"))
+        || url.len() >= 2
+            && url.as_bytes()[..2]
+                .iter()
+                .all(|b| matches!(b, b'/' | b'\\'))
+}
+
+/// If a link URL points to another page of the site, get its route from the
+/// root of the site. Cross-references are made relative with `\pediaRelTop`,
+/// which climbs from the page to the root, as in `../e/x#frag` from an entry
+/// page or `../../explain/slug/` from an explainer. Links to entry pages by
+/// their output paths, as in `./entry-x.html#frag`, are also recognized.
+fn internal_link_route(url: &str) -> Option<String> {
+    let (path, fragment) = url.split_at(url.find('#').unwrap_or(url.len()));
+    let mut rest = path.strip_prefix("./").unwrap_or(path);
+    let mut climbed = false;
+
+    while let Some(r) = rest.strip_prefix("../") {
+        rest = r;
+        climbed = true;
+    }
+
+    if rest.split('/').any(|s| s == "." || s == "..") {
+        return None;
+    }
+
+    if !climbed {
+        if rest.contains('/') || entry_name_for_output(rest).is_none() {
+            return None;
+        }
+
+        return Some(format!("/{}{fragment}", output_site_path(rest)));
+    }
+
+    let is_page = match rest.strip_prefix("e/") {
+        Some(entry) => !entry.is_empty() && !entry.contains('/'),
+        None => rest.starts_with("explain/"),
+    };
+
+    is_page.then(|| format!("/{rest}{fragment}"))
+}
+
+/// Check whether an inline style is free of constructs that can load
+/// resources or run code in some browsers.
+fn is_safe_style(style: &str) -> bool {
+    let style = style.to_ascii_lowercase();
+
+    // Backslashes could escape any of the other patterns.
+    ![
+        "\\",
+        "url(",
+        "expression",
+        "@import",
+        "behavior",
+        "-moz-binding",
+    ]
+    .iter()
+    .any(|p| style.contains(p))
+}
+
+/// Decode the character references in a raw attribute value. Unrecognized
+/// references are left as-is, and will be escaped on output so that browsers
+/// don't decode them either.
+fn decode_attribute(raw: &str) -> String {
+    let mut value = String::with_capacity(raw.len());
+    let mut rest = raw;
+
+    while let Some(amp) = rest.find('&') {
+        value.push_str(&rest[..amp]);
+        rest = &rest[amp + 1..];
+
+        match decode_reference(rest) {
+            Some((c, len)) => {
+                value.push(c);
+                rest = &rest[len..];
+            }
+
+            None => value.push('&'),
+        }
+    }
+
+    value.push_str(rest);
+    value
+}
+
+/// Append an attribute value to `out`, escaping it for use in a
+/// double-quoted attribute.
+fn push_escaped(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn postprocess_1() {
+        let html = r#"<p class="x" onclick="evil()">Hi<script>alert("<p>")</script> there</p>
+<a href="./entry-dump.html#syntax">d</a><a target="_blank" href="https://example.com/">e</a>
+<a href=" jav&#x61;script:alert(1)">j</a><img src=x.png alt='a "b"' / ><br/>1 < 2"#;
+        assert_eq!(
+            postprocess(html),
+            r#"<p class="x">Hi there</p>
+<a href="/e/dump#syntax">d</a><a target="_blank" href="https://example.com/" rel="noopener">e</a>
+<a>j</a><img src="x.png" alt="a &quot;b&quot;"><br />1 &lt; 2"#
+        );
+    }
+
+    #[test]
+    fn postprocess_2() {
+        // Markup that browsers parse in surprising ways.
+        let html = "<noscript><p title=\"</noscript><img src=x onerror=alert(1)>\"></noscript>\
+            <svg><set attributeName=href to=javascript:alert(1) /><a xlink:href=\"&#106;avascript:x\">s</a></svg>\
+            <a href=\"java\tscript:x\" rel=nofollow>t</a><p style=\"background: u\\72l(x)\"><!-- c --><x";
+        assert_eq!(
+            postprocess(html),
+            "<img src=\"x\">\"><svg><a>s</a></svg><a rel=\"nofollow\">t</a><p>"
+        );
+    }
+
+    #[test]
+    fn internal_links_1() {
+        // As emitted by `\pediaLinkRef` and `\cs` on entry pages and
+        // explainers, including for an unresolved reference.
+        let html = r#"<a href="../e/dump#syntax">d</a><a href="../../explain/why-tex/">w</a>
+<a href="../../e/dump">x</a><a href="../ENTRYREF">u</a><a href="../e/../../etc">z</a>"#;
+        assert_eq!(
+            postprocess(html),
+            r#"<a href="/e/dump#syntax">d</a><a href="/explain/why-tex/">w</a>
+<a href="/e/dump">x</a><a href="../ENTRYREF">u</a><a href="../e/../../etc">z</a>"#
+        );
+    }
+}
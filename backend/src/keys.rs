@@ -0,0 +1,85 @@
+//! Validation of the names under which published objects are stored.
+//!
+//! Output paths come from the TeX of user-editable documents, and asset bucket
+//! keys from requests to the nexus, so neither can be trusted to stay within
+//! the part of the storage that it's meant for. Anything that ends up in an
+//! object key or a URL path is checked with the functions here first.
+
+use anyhow::{Context, Result, bail, ensure};
+
+/// The maximum length of a single key segment, in bytes.
+pub const MAX_SEGMENT_LEN: usize = 255;
+
+/// The maximum length of a full key path, in bytes. This is the limit on S3
+/// object keys.
+pub const MAX_PATH_LEN: usize = 1024;
+
+/// Characters that may not appear in key segments, in addition to control
+/// characters. Besides the path separators, these have special meanings in
+/// URLs.
+const FORBIDDEN_CHARS: &[char] = &['/', '\\', '?', '#', '%'];
+
+/// Check that a string can be used as a single segment of an object key or URL
+/// path, such as an asset bucket key, an asset name or a document ID.
+pub fn validate_key_segment(segment: &str) -> Result<()> {
+    ensure!(!segment.is_empty(), "key segment is empty");
+    ensure!(
+        segment.len() <= MAX_SEGMENT_LEN,
+        "key segment is longer than {MAX_SEGMENT_LEN} bytes"
+    );
+    ensure!(
+        segment != "." && segment != "..",
+        "key segment `{segment}` is a relative path component"
+    );
+
+    if let Some(c) = segment
+        .chars()
+        .find(|c| c.is_control() || FORBIDDEN_CHARS.contains(c))
+    {
+        bail!("key segment {segment:?} contains forbidden character {c:?}");
+    }
+
+    Ok(())
+}
+
+/// Check that a string is a relative path made of valid key segments separated
+/// by slashes, such as an output path or a full object key.
+pub fn validate_key_path(path: &str) -> Result<()> {
+    ensure!(
+        path.len() <= MAX_PATH_LEN,
+        "key path is longer than {MAX_PATH_LEN} bytes"
+    );
+
+    for segment in path.split('/') {
+        validate_key_segment(segment).with_context(|| format!("invalid key path {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_1() {
+        assert!(validate_key_segment("tdux-fonts.css").is_ok());
+        assert!(validate_key_segment("gxhZkppeZEXBb7LXnwvHWEuavAd").is_ok());
+
+        for bad in ["", ".", "..", "a/b", "a\\b", "a\nb", "a?b", "a#b", "%2e%2e"] {
+            assert!(validate_key_segment(bad).is_err(), "{bad:?}");
+        }
+
+        assert!(validate_key_segment(&"x".repeat(MAX_SEGMENT_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn paths_1() {
+        assert!(validate_key_path("entry-dump.html").is_ok());
+        assert!(validate_key_path("explain/why-tex/index.html").is_ok());
+
+        for bad in ["", "/etc/passwd", "a//b", "a/", "../other/x.html", "a/./b"] {
+            assert!(validate_key_path(bad).is_err(), "{bad:?}");
+        }
+    }
+}
@@ -2,7 +2,18 @@
 
 use serde::{Deserialize, Serialize};
 
+pub mod auth;
+pub mod config;
+pub mod html;
+pub mod keys;
+pub mod logging;
 pub mod metadata;
+pub mod monitoring;
+pub mod nexus;
+pub mod repo;
+pub mod search;
+pub mod serve;
+pub mod worker;
 
 /// The request to the Nexus server's `POST /pass1` endpoint, which is invoked
 /// when a compiler worker has completed a first compilation pass. This provides
@@ -40,6 +51,11 @@ pub struct NexusPostPass1Response {
     /// and follow up with confirmation if/when it succeeds, returning the
     /// sequence number that it's been provided.
     pub preserve_assets: Option<usize>,
+
+    /// Problems with the `pedia.txt` metadata. The offending records were
+    /// ignored.
+    #[serde(default)]
+    pub metadata_errors: Vec<metadata::PediaTxtError>,
 }
 
 /// The request to the Nexus server's `POST /assets_uploaded` endpoint, which is
@@ -52,7 +68,9 @@ pub struct NexusPostAssetsUploadedRequest {
     pub seq_num: usize,
 
     /// The unique key prefix under which the assets are stored in the bucket.
-    /// Should not contain any slashes.
+    /// This must be a valid single key segment, as checked by
+    /// [`keys::validate_key_segment`]; in particular, it may not contain any
+    /// slashes.
     pub bucket_key: String,
 }
 
@@ -60,6 +78,60 @@ pub struct NexusPostAssetsUploadedRequest {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct NexusPostAssetsUploadedResponse {}
 
+/// The request to the Nexus server's `POST /entries_uploaded` endpoint, which is
+/// invoked when a compiler worker has uploaded the HTML of the entries that it
+/// generated.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusPostEntriesUploadedRequest {
+    /// The automerge-repo ID of the document that was compiled, in its
+    /// base58check representation.
+    pub doc_id: String,
+
+    /// The ID of the compilation job (originally assigned by Faktory).
+    pub job_id: String,
+
+    /// The entries that were uploaded.
+    pub entries: Vec<NexusUploadedEntry>,
+}
+
+/// An entry page uploaded by a compiler worker.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusUploadedEntry {
+    /// The name of the entry.
+    pub name: String,
+
+    /// The text content of the entry's HTML, for searching.
+    pub text: String,
+}
+
+/// The response from the Nexus server's `POST /entries_uploaded` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusPostEntriesUploadedResponse {}
+
+/// The request to the Nexus server's `POST /rename_entry` endpoint, which
+/// renames an entry, leaving behind an alias so that the old name keeps
+/// working.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusPostRenameEntryRequest {
+    /// The old name of the entry.
+    pub old: String,
+
+    /// The new name of the entry. An entry with this name must already have
+    /// been defined.
+    pub new: String,
+}
+
+/// The response from the Nexus server's `POST /rename_entry` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusPostRenameEntryResponse {
+    /// "ok" if success, a brief error message if not.
+    pub status: String,
+
+    /// The IDs of the documents that reference the entry. They should be
+    /// recompiled so that their references use the new name.
+    pub referencing_doc_ids: Vec<String>,
+}
+
 /// The response to the Nexus server's `GET /entry/{name}` endpoint.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct NexusGetEntryResponse {
@@ -71,4 +143,217 @@ pub struct NexusGetEntryResponse {
 
     /// The title of the entry.
     pub title: String,
+
+    /// The canonical name of the entry. This differs from the requested name
+    /// if that was an alias.
+    pub name: String,
+
+    /// A plain-text summary of the entry, if one was provided.
+    #[serde(default)]
+    pub summary: Option<String>,
+
+    /// The categories to which the entry belongs.
+    #[serde(default)]
+    pub categories: Vec<String>,
+}
+
+/// The response to the Nexus server's `GET /indices` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusGetIndicesResponse {
+    /// The names of all indices that have at least one definition, in sorted
+    /// order.
+    pub indices: Vec<String>,
+}
+
+/// The response to the Nexus server's `GET /index/{index}` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusGetIndexResponse {
+    /// The name of the index.
+    pub index: String,
+
+    /// One page of the entries of the index, in sorted order.
+    pub entries: Vec<NexusIndexEntry>,
+
+    /// If there are more entries, the value of the `after` parameter that
+    /// will fetch the next page.
+    pub next_after: Option<String>,
+}
+
+/// An entry in an index, as returned by the Nexus server's `GET /index/{index}`
+/// endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusIndexEntry {
+    /// The name of the entry.
+    pub name: String,
+
+    /// The location of the entry's definition: the path of the web page
+    /// containing it relative to the root of the site, such as `e/{name}`,
+    /// followed by a URL fragment if there is one. Empty if the entry has text
+    /// but no definition.
+    pub location: String,
+
+    /// The "at-plain" text of the entry.
+    pub atplain: String,
+
+    /// The TeX text of the entry.
+    pub tex: String,
+
+    /// The ID of the document containing the entry's definition, if it's
+    /// known.
+    #[serde(default)]
+    pub doc_id: Option<String>,
+}
+
+/// The response to the Nexus server's `GET /backlinks/{index}/{entry}`
+/// endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusGetBacklinksResponse {
+    /// The name of the index.
+    pub index: String,
+
+    /// The name of the entry. This differs from the requested name if that was
+    /// an alias.
+    pub entry: String,
+
+    /// The outputs that reference the entry, sorted by output name.
+    pub backlinks: Vec<NexusBacklink>,
+}
+
+/// A reference to an index entry, as returned by the Nexus server's
+/// `GET /backlinks/{index}/{entry}` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusBacklink {
+    /// The ID of the document containing the reference.
+    pub doc_id: String,
+
+    /// The name of the output file containing the reference.
+    pub output: String,
+
+    /// The name of the entry page containing the reference, if the output is
+    /// an entry page.
+    pub entry: Option<String>,
+}
+
+/// The response to the Nexus server's `GET /toc/{entry}` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusGetTocResponse {
+    /// The name of the entry. This differs from the requested name if that was
+    /// an alias.
+    pub entry: String,
+
+    /// The section headings of the entry page, in document order.
+    pub sections: Vec<NexusTocSection>,
+}
+
+/// A section heading, as returned by the Nexus server's `GET /toc/{entry}`
+/// endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusTocSection {
+    /// The name of the section in the `sections` index, of the form
+    /// `{page}/{id}`.
+    pub name: String,
+
+    /// The plain-text title of the section.
+    pub title: String,
+
+    /// The location of the section: the path of its page relative to the root
+    /// of the site, followed by its URL fragment.
+    pub location: String,
+}
+
+/// The response to the Nexus server's `GET /references/{entry}` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusGetReferencesResponse {
+    /// The name of the entry. This differs from the requested name if that was
+    /// an alias.
+    pub entry: String,
+
+    /// The references cited by the entry page, sorted by citation key.
+    pub references: Vec<NexusReference>,
+}
+
+/// The response to the Nexus server's `GET /bibliography` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusGetBibliographyResponse {
+    /// All of the references that have been declared, sorted by citation key.
+    pub references: Vec<NexusReference>,
+}
+
+/// A reference to an external source, as returned by the Nexus server's
+/// `GET /references/{entry}` and `GET /bibliography` endpoints.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusReference {
+    /// The citation key of the reference: its name in the `bib` index.
+    pub key: String,
+
+    /// The plain-text label that citations of the reference display.
+    pub label: String,
+
+    /// The plain-text formatted reference.
+    pub reference: String,
+
+    /// The location where the reference was declared: the path of its page
+    /// relative to the root of the site, followed by its URL fragment.
+    pub location: String,
+}
+
+/// The response to the Nexus server's `GET /cs/{name}` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusGetControlSequenceResponse {
+    /// The control sequence, including its leading backslash.
+    pub control_sequence: String,
+
+    /// The name of the entry page documenting the control sequence, if it's
+    /// documented on an entry page.
+    pub entry: Option<String>,
+
+    /// The location of the control sequence's documentation: the path of its
+    /// page relative to the root of the site, followed by a URL fragment if
+    /// there is one. Empty if the control sequence isn't documented.
+    pub location: String,
+}
+
+/// The response to the Nexus server's `GET /categories` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusGetCategoriesResponse {
+    /// The names of all categories that have at least one member, in sorted
+    /// order.
+    pub categories: Vec<String>,
+}
+
+/// The response to the Nexus server's `GET /category/{name}` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusGetCategoryResponse {
+    /// The name of the category.
+    pub name: String,
+
+    /// The names of the entries in the category, in sorted order.
+    pub entries: Vec<String>,
+}
+
+/// The response to the Nexus server's `GET /search` endpoint.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct NexusGetSearchResponse {
+    /// The search query.
+    pub query: String,
+
+    /// The matching entries, best first.
+    pub hits: Vec<NexusSearchHit>,
+}
+
+/// A search result, as returned by the Nexus server's `GET /search` endpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NexusSearchHit {
+    /// The name of the matching entry.
+    pub name: String,
+
+    /// The plain-text title of the entry.
+    pub title: String,
+
+    /// An excerpt of the entry's text, showing where the query matched if
+    /// possible.
+    pub snippet: String,
+
+    /// The URL fragment of the entry's definition, if it has one.
+    pub fragment: String,
 }
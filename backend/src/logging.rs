@@ -0,0 +1,65 @@
+//! Logging setup shared by the Tectonopedia binaries.
+//!
+//! Logs are emitted with [`tracing`]. Filtering is controlled by the standard
+//! `RUST_LOG` environment variable, using [`EnvFilter`] directive syntax; by
+//! default, everything from the Tectonopedia crates at the `info` level is
+//! logged. If `TTPEDIA_LOG_FORMAT` is set to `json`, events are written as
+//! JSON lines suitable for a log aggregator rather than as human-readable text.
+//!
+//! Compilations are correlated across services by their Faktory job ID. The
+//! repo server assigns it when a document is submitted, the compiler worker
+//! attaches it to the span of each compilation, and the worker passes it along
+//! to the nexus in [`crate::NexusPostPass1Request::job_id`]. Filtering the
+//! logs of all of the services for `job_id` therefore follows one submission
+//! from end to end.
+
+use anyhow::{Result, bail};
+use tracing_subscriber::{
+    EnvFilter, fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt,
+};
+
+/// The environment variable that selects the log output format.
+pub const LOG_FORMAT_VAR: &str = "TTPEDIA_LOG_FORMAT";
+
+/// Install the global logger. `crate_name` should be the name of the calling
+/// binary's crate, so that its events are logged by default too.
+pub fn init(crate_name: &str) -> Result<()> {
+    install(crate_name, std::io::stdout)
+}
+
+/// Install the global logger, writing to standard error. This is for programs
+/// whose standard output is reserved for other purposes, such as the language
+/// server.
+pub fn init_stderr(crate_name: &str) -> Result<()> {
+    install(crate_name, std::io::stderr)
+}
+
+fn install<W>(crate_name: &str, writer: W) -> Result<()>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        format!("ttpedia_backend=info,{crate_name}=info,tower_http=info").into()
+    });
+
+    let json = match std::env::var(LOG_FORMAT_VAR) {
+        Ok(v) if v == "json" => true,
+        Ok(v) if v.is_empty() || v == "text" => false,
+        Ok(v) => bail!("illegal value `{v}` for environment variable {LOG_FORMAT_VAR}"),
+        Err(_) => false,
+    };
+
+    let registry = tracing_subscriber::registry().with(filter);
+
+    if json {
+        registry
+            .with(tracing_subscriber::fmt::layer().json().with_writer(writer))
+            .try_init()?;
+    } else {
+        registry
+            .with(tracing_subscriber::fmt::layer().with_writer(writer))
+            .try_init()?;
+    }
+
+    Ok(())
+}
@@ -2,7 +2,14 @@
 // Licensed under the MIT License
 
 //! Metadata entries output to the `pedia.txt` file by the TeX passes.
+//!
+//! Each entry is written by TeX as a line of the form `\cseq{term}{term}...`.
+//! Characters that TeX considers unprintable show up in the `^^` notation
+//! (`^^M`, `^^e9`, `^^^^2014`, and so on), and long lines are wrapped, so
+//! [`PediaTxtReader`] and [`MetadatumBuf::parse`] undo both of those things.
 
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, fmt, io::BufRead};
 use tectonic_errors::prelude::*;
 
 pub type IndexRefFlags = u8;
@@ -63,9 +70,45 @@ pub enum Metadatum<'a> {
         /// The "at-plain" representation of the entry.
         atplain: &'a str,
     },
+
+    /// Give a plain-text summary of the current output, suitable for previews
+    /// and search results.
+    Summary(&'a str),
+
+    /// Declare that the current output belongs to the named category.
+    Category(&'a str),
+
+    /// Declare an alternative name for an index entry. References to the alias
+    /// are resolved as if they were references to the target.
+    Alias {
+        /// The name of the index in which the alias is being declared.
+        index: &'a str,
+
+        /// The alternative name.
+        alias: &'a str,
+
+        /// The name of the entry that the alias stands for.
+        target: &'a str,
+    },
+
+    /// Give the formatted form of a reference to an external source, which can
+    /// be cited as an entry in the `bib` index.
+    Reference {
+        /// The citation key of the reference.
+        key: &'a str,
+
+        /// The full TeX representation of the reference.
+        tex: &'a str,
+
+        /// The "at-plain" representation of the reference.
+        atplain: &'a str,
+    },
 }
 
 impl<'a> Metadatum<'a> {
+    /// Parse a single line of metadata. The terms are returned as they appear
+    /// in the line, with any `^^` escapes intact; use [`MetadatumBuf::parse`]
+    /// to decode them.
     pub fn parse(s: &'a str) -> Result<Self> {
         // It seems that we can't use FromStr because we can't link up the
         // lifetime in the input argument here to the impl lifetime.
@@ -137,6 +180,50 @@ impl<'a> Metadatum<'a> {
                 })
             }
 
+            "summary" => {
+                ensure!(
+                    terms.len() == 1,
+                    "malformed metadata line {:?}: \\summary must be followed by exactly 1 braced term",
+                    s
+                );
+                Ok(Metadatum::Summary(terms[0]))
+            }
+
+            "category" => {
+                ensure!(
+                    terms.len() == 1,
+                    "malformed metadata line {:?}: \\category must be followed by exactly 1 braced term",
+                    s
+                );
+                Ok(Metadatum::Category(terms[0]))
+            }
+
+            "alias" => {
+                ensure!(
+                    terms.len() == 3,
+                    "malformed metadata line {:?}: \\alias must be followed by exactly 3 braced terms",
+                    s
+                );
+                Ok(Metadatum::Alias {
+                    index: terms[0],
+                    alias: terms[1],
+                    target: terms[2],
+                })
+            }
+
+            "reference" => {
+                ensure!(
+                    terms.len() == 3,
+                    "malformed metadata line {:?}: \\reference must be followed by exactly 3 braced terms",
+                    s
+                );
+                Ok(Metadatum::Reference {
+                    key: terms[0],
+                    tex: terms[1],
+                    atplain: terms[2],
+                })
+            }
+
             _ => {
                 bail!("unrecognized metadata line {:?}", s)
             }
@@ -144,6 +231,710 @@ impl<'a> Metadatum<'a> {
     }
 }
 
+impl fmt::Display for Metadatum<'_> {
+    /// Write this entry as a line of `pedia.txt`, without the trailing newline.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Metadatum::Output(path) => write_cseq_line(f, "output", &[path]),
+
+            Metadatum::IndexDef {
+                index,
+                entry,
+                fragment,
+            } => write_cseq_line(f, "idef", &[index, entry, fragment]),
+
+            Metadatum::IndexRef {
+                index,
+                entry,
+                flags,
+            } => {
+                let mut flags_term = String::new();
+
+                if (flags & IndexRefFlag::NeedsLoc as u8) != 0 {
+                    flags_term.push('l');
+                }
+
+                if (flags & IndexRefFlag::NeedsText as u8) != 0 {
+                    flags_term.push('t');
+                }
+
+                write_cseq_line(f, "iref", &[index, entry, &flags_term])
+            }
+
+            Metadatum::IndexText {
+                index,
+                entry,
+                tex,
+                atplain,
+            } => write_cseq_line(f, "itext", &[index, entry, tex, atplain]),
+
+            Metadatum::Summary(text) => write_cseq_line(f, "summary", &[text]),
+
+            Metadatum::Category(name) => write_cseq_line(f, "category", &[name]),
+
+            Metadatum::Alias {
+                index,
+                alias,
+                target,
+            } => write_cseq_line(f, "alias", &[index, alias, target]),
+
+            Metadatum::Reference { key, tex, atplain } => {
+                write_cseq_line(f, "reference", &[key, tex, atplain])
+            }
+        }
+    }
+}
+
+/// An owned version of [`Metadatum`], for keeping metadata around after the
+/// text that they were parsed from has gone away.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum MetadatumBuf {
+    /// See [`Metadatum::Output`].
+    Output(String),
+
+    /// See [`Metadatum::IndexDef`].
+    IndexDef {
+        index: String,
+        entry: String,
+        fragment: String,
+    },
+
+    /// See [`Metadatum::IndexRef`].
+    IndexRef {
+        index: String,
+        entry: String,
+        flags: IndexRefFlags,
+    },
+
+    /// See [`Metadatum::IndexText`].
+    IndexText {
+        index: String,
+        entry: String,
+        tex: String,
+        atplain: String,
+    },
+
+    /// See [`Metadatum::Summary`].
+    Summary(String),
+
+    /// See [`Metadatum::Category`].
+    Category(String),
+
+    /// See [`Metadatum::Alias`].
+    Alias {
+        index: String,
+        alias: String,
+        target: String,
+    },
+
+    /// See [`Metadatum::Reference`].
+    Reference {
+        key: String,
+        tex: String,
+        atplain: String,
+    },
+}
+
+impl MetadatumBuf {
+    /// Parse a single line of metadata, decoding any `^^` escapes in its
+    /// terms.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut m: MetadatumBuf = Metadatum::parse(s)?.into();
+
+        for term in m.terms_mut() {
+            if let Cow::Owned(decoded) = decode_escapes(term) {
+                *term = decoded;
+            }
+        }
+
+        Ok(m)
+    }
+
+    /// Get mutable references to all of the textual terms of this entry.
+    fn terms_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            MetadatumBuf::Output(path) => vec![path],
+
+            MetadatumBuf::IndexDef {
+                index,
+                entry,
+                fragment,
+            } => vec![index, entry, fragment],
+
+            MetadatumBuf::IndexRef { index, entry, .. } => vec![index, entry],
+
+            MetadatumBuf::IndexText {
+                index,
+                entry,
+                tex,
+                atplain,
+            } => vec![index, entry, tex, atplain],
+
+            MetadatumBuf::Summary(text) => vec![text],
+
+            MetadatumBuf::Category(name) => vec![name],
+
+            MetadatumBuf::Alias {
+                index,
+                alias,
+                target,
+            } => vec![index, alias, target],
+
+            MetadatumBuf::Reference { key, tex, atplain } => vec![key, tex, atplain],
+        }
+    }
+
+    /// Borrow this entry as a [`Metadatum`].
+    pub fn as_metadatum(&self) -> Metadatum<'_> {
+        match self {
+            MetadatumBuf::Output(path) => Metadatum::Output(path),
+
+            MetadatumBuf::IndexDef {
+                index,
+                entry,
+                fragment,
+            } => Metadatum::IndexDef {
+                index,
+                entry,
+                fragment,
+            },
+
+            MetadatumBuf::IndexRef {
+                index,
+                entry,
+                flags,
+            } => Metadatum::IndexRef {
+                index,
+                entry,
+                flags: *flags,
+            },
+
+            MetadatumBuf::IndexText {
+                index,
+                entry,
+                tex,
+                atplain,
+            } => Metadatum::IndexText {
+                index,
+                entry,
+                tex,
+                atplain,
+            },
+
+            MetadatumBuf::Summary(text) => Metadatum::Summary(text),
+
+            MetadatumBuf::Category(name) => Metadatum::Category(name),
+
+            MetadatumBuf::Alias {
+                index,
+                alias,
+                target,
+            } => Metadatum::Alias {
+                index,
+                alias,
+                target,
+            },
+
+            MetadatumBuf::Reference { key, tex, atplain } => {
+                Metadatum::Reference { key, tex, atplain }
+            }
+        }
+    }
+}
+
+impl From<Metadatum<'_>> for MetadatumBuf {
+    fn from(m: Metadatum<'_>) -> Self {
+        match m {
+            Metadatum::Output(path) => MetadatumBuf::Output(path.to_owned()),
+
+            Metadatum::IndexDef {
+                index,
+                entry,
+                fragment,
+            } => MetadatumBuf::IndexDef {
+                index: index.to_owned(),
+                entry: entry.to_owned(),
+                fragment: fragment.to_owned(),
+            },
+
+            Metadatum::IndexRef {
+                index,
+                entry,
+                flags,
+            } => MetadatumBuf::IndexRef {
+                index: index.to_owned(),
+                entry: entry.to_owned(),
+                flags,
+            },
+
+            Metadatum::IndexText {
+                index,
+                entry,
+                tex,
+                atplain,
+            } => MetadatumBuf::IndexText {
+                index: index.to_owned(),
+                entry: entry.to_owned(),
+                tex: tex.to_owned(),
+                atplain: atplain.to_owned(),
+            },
+
+            Metadatum::Summary(text) => MetadatumBuf::Summary(text.to_owned()),
+
+            Metadatum::Category(name) => MetadatumBuf::Category(name.to_owned()),
+
+            Metadatum::Alias {
+                index,
+                alias,
+                target,
+            } => MetadatumBuf::Alias {
+                index: index.to_owned(),
+                alias: alias.to_owned(),
+                target: target.to_owned(),
+            },
+
+            Metadatum::Reference { key, tex, atplain } => MetadatumBuf::Reference {
+                key: key.to_owned(),
+                tex: tex.to_owned(),
+                atplain: atplain.to_owned(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for MetadatumBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_metadatum().fmt(f)
+    }
+}
+
+/// A problem with the `pedia.txt` metadata, associated with the line on which
+/// it occurred.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PediaTxtError {
+    /// The 1-based number of the line on which the offending record started.
+    pub line: usize,
+
+    /// A description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for PediaTxtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pedia.txt line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for PediaTxtError {}
+
+/// Read the records of a `pedia.txt` file one at a time.
+///
+/// The iterator yields each record along with the number of the line on which
+/// it started. TeX wraps long `\write` output across multiple lines, so a line
+/// that doesn't start a new record (by starting with a backslash), or a record
+/// with unclosed braces, is joined with the lines that follow it.
+///
+/// By default, the iterator yields an error for each record that can't be
+/// parsed and then carries on. In lenient mode, bad records are skipped, and
+/// the errors are collected for later inspection with [`Self::errors`]. Errors
+/// reading the underlying stream are yielded in either mode.
+pub struct PediaTxtReader<R> {
+    inner: R,
+    lenient: bool,
+    line_no: usize,
+    lookahead: Option<String>,
+    errors: Vec<PediaTxtError>,
+}
+
+impl<R: BufRead> PediaTxtReader<R> {
+    pub fn new(inner: R) -> Self {
+        PediaTxtReader {
+            inner,
+            lenient: false,
+            line_no: 0,
+            lookahead: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Set whether bad records should be skipped rather than reported.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// The errors that have been skipped in lenient mode so far.
+    pub fn errors(&self) -> &[PediaTxtError] {
+        &self.errors
+    }
+
+    /// Consume the reader, returning the errors that were skipped in lenient
+    /// mode.
+    pub fn into_errors(self) -> Vec<PediaTxtError> {
+        self.errors
+    }
+
+    /// Read the next nonempty line, without its line ending.
+    fn next_line(&mut self) -> Option<Result<String, PediaTxtError>> {
+        if let Some(line) = self.lookahead.take() {
+            return Some(Ok(line));
+        }
+
+        loop {
+            let mut line = String::new();
+            self.line_no += 1;
+
+            match self.inner.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => {
+                    return Some(Err(PediaTxtError {
+                        line: self.line_no,
+                        message: format!("failed to read: {e}"),
+                    }));
+                }
+            }
+
+            let trimmed_len = line.trim_end_matches(['\r', '\n']).len();
+            line.truncate(trimmed_len);
+
+            if !line.is_empty() {
+                return Some(Ok(line));
+            }
+        }
+    }
+
+    /// Read the text of the next complete record, and the line on which it
+    /// started.
+    fn next_record(&mut self) -> Option<Result<(usize, String), PediaTxtError>> {
+        let mut text = match self.next_line()? {
+            Ok(l) => l,
+            Err(e) => return Some(Err(e)),
+        };
+        let line = self.line_no;
+
+        loop {
+            let needs_more = brace_depth(&text) > 0;
+
+            match self.next_line() {
+                None => break,
+                Some(Err(e)) => return Some(Err(e)),
+
+                Some(Ok(next)) => {
+                    if needs_more || !next.starts_with('\\') {
+                        text.push_str(&next);
+                    } else {
+                        self.lookahead = Some(next);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Some(Ok((line, text)))
+    }
+}
+
+impl<R: BufRead> Iterator for PediaTxtReader<R> {
+    type Item = Result<(usize, MetadatumBuf), PediaTxtError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // I/O errors are always reported, since skipping them could mean
+            // spinning forever.
+            let (line, text) = match self.next_record()? {
+                Ok(r) => r,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match MetadatumBuf::parse(&text) {
+                Ok(m) => return Some(Ok((line, m))),
+
+                Err(e) => {
+                    let e = PediaTxtError {
+                        line,
+                        message: format!("{e:#}"),
+                    };
+
+                    if !self.lenient {
+                        return Some(Err(e));
+                    }
+
+                    self.errors.push(e);
+                }
+            }
+        }
+    }
+}
+
+/// Get the number of braces that are left open at the end of `s`.
+fn brace_depth(s: &str) -> usize {
+    let mut depth = 0usize;
+
+    for c in s.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    depth
+}
+
+/// Write a line of the form `\CSEQ{A}{B}{C}`, the inverse of
+/// [`parse_cseq_line`].
+fn write_cseq_line(f: &mut fmt::Formatter<'_>, cseq: &str, terms: &[&str]) -> fmt::Result {
+    write!(f, "\\{cseq}")?;
+
+    for term in terms {
+        f.write_str("{")?;
+        write_term(f, term)?;
+        f.write_str("}")?;
+    }
+
+    Ok(())
+}
+
+/// Write out a term, escaping anything that would prevent it from being read
+/// back as a single braced term on a single line.
+///
+/// Balanced braces are written as-is, like TeX itself would write them. Braces
+/// without a partner and control characters are written in TeX's `^^xx`
+/// notation, as is any `^` that would otherwise be read as the start of an
+/// escape.
+fn write_term(f: &mut fmt::Formatter<'_>, term: &str) -> fmt::Result {
+    // Find the braces that don't have partners.
+
+    let mut open = Vec::new();
+    let mut unbalanced = Vec::new();
+
+    for (i, c) in term.char_indices() {
+        match c {
+            '{' => open.push(i),
+            '}' if open.pop().is_none() => unbalanced.push(i),
+            _ => {}
+        }
+    }
+
+    unbalanced.extend(open);
+
+    // Figure out what needs escaping. Working backwards lets us see whether a
+    // `^` is followed by another `^`, either literally or as part of an escape.
+
+    let mut chars: Vec<(char, bool)> = term
+        .char_indices()
+        .map(|(i, c)| {
+            let escape = match c {
+                '{' | '}' => unbalanced.contains(&i),
+                _ => c.is_ascii_control(),
+            };
+            (c, escape)
+        })
+        .collect();
+
+    for i in (0..chars.len()).rev() {
+        if chars[i].0 == '^'
+            && let Some(&(next, next_escaped)) = chars.get(i + 1)
+        {
+            chars[i].1 = next == '^' || next_escaped;
+        }
+    }
+
+    // Now write it out.
+
+    for (c, escape) in chars {
+        if escape {
+            write!(f, "^^{:02x}", c as u32)?;
+        } else {
+            write!(f, "{c}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Decode TeX's `^^` notation for special characters: `^^^^^^xxxxxx`,
+/// `^^^^xxxx`, and `^^xx` with lowercase hexadecimal digits, and `^^c` for
+/// ASCII characters `c`, which denotes the character whose code differs from
+/// that of `c` by 64. A `^^` that doesn't form a valid escape is left alone.
+pub fn decode_escapes(s: &str) -> Cow<'_, str> {
+    if !s.contains("^^") {
+        return Cow::Borrowed(s);
+    }
+
+    let mut decoded = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(i) = rest.find("^^") {
+        decoded.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        if let Some((c, len)) = decode_one_escape(rest) {
+            decoded.push(c);
+            rest = &rest[len..];
+        } else {
+            decoded.push('^');
+            rest = &rest[1..];
+        }
+    }
+
+    decoded.push_str(rest);
+    Cow::Owned(decoded)
+}
+
+/// Decode the escape at the start of `s`, which must start with `^^`,
+/// returning the character and the length of the escape.
+fn decode_one_escape(s: &str) -> Option<(char, usize)> {
+    for n in [6, 4, 2] {
+        let Some(digits) = s.get(n..2 * n) else {
+            continue;
+        };
+
+        if !s[..n].bytes().all(|b| b == b'^') {
+            continue;
+        }
+
+        if !digits
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        {
+            continue;
+        }
+
+        if let Some(c) = char::from_u32(u32::from_str_radix(digits, 16).unwrap()) {
+            return Some((c, 2 * n));
+        }
+    }
+
+    match s[2..].chars().next() {
+        Some(c) if c.is_ascii() => Some(((c as u8 ^ 0x40) as char, 3)),
+        _ => None,
+    }
+}
+
+/// The characters that are special to TeX, and the letters that stand for them
+/// in the "at-escaping" syntax that `at_escaping.tex` decodes. `@` itself is
+/// escaped as `@@`.
+const AT_ESCAPES: &[(char, char)] = &[
+    ('\\', 'B'),
+    ('{', 'L'),
+    ('}', 'R'),
+    ('$', 'M'),
+    ('&', 'A'),
+    ('#', 'H'),
+    ('^', 'C'),
+    ('_', 'U'),
+    ('~', 'N'),
+    ('%', 'P'),
+    ('`', 'T'),
+];
+
+/// At-escape some text, so that it can be written into generated TeX code and
+/// turned back into literal characters by `\pediaAtDecodeVar`. Control
+/// characters can't make that trip, so they become spaces.
+pub fn at_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if c == '@' {
+            escaped.push_str("@@");
+        } else if let Some((_, code)) = AT_ESCAPES.iter().find(|(special, _)| *special == c) {
+            escaped.push('@');
+            escaped.push(*code);
+        } else if c.is_control() {
+            escaped.push(' ');
+        } else {
+            escaped.push(c);
+        }
+    }
+
+    escaped
+}
+
+/// Decode at-escaped text, the inverse of [`at_escape`]. As in TeX, an `@` that
+/// doesn't start a valid escape is left alone.
+pub fn at_unescape(s: &str) -> Cow<'_, str> {
+    if !s.contains('@') {
+        return Cow::Borrowed(s);
+    }
+
+    let mut decoded = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '@' {
+            decoded.push(c);
+            continue;
+        }
+
+        let special = match chars.peek() {
+            Some('@') => Some('@'),
+            Some(next) => AT_ESCAPES
+                .iter()
+                .find(|(_, code)| code == next)
+                .map(|(special, _)| *special),
+            None => None,
+        };
+
+        match special {
+            Some(special) => {
+                decoded.push(special);
+                chars.next();
+            }
+            None => decoded.push('@'),
+        }
+    }
+
+    Cow::Owned(decoded)
+}
+
+/// At-escape the name of an index or entry for use in the name of a control
+/// sequence, matching `\pediaAtEncode`. Spaces become `@S`, since runs of them
+/// would otherwise be collapsed when the generated TeX is read back in. Returns
+/// `None` if the name contains control characters, which can't be encoded.
+pub fn at_escape_name(s: &str) -> Option<String> {
+    if s.chars().any(char::is_control) {
+        return None;
+    }
+
+    Some(at_escape(s).replace(' ', "@S"))
+}
+
+/// Check whether some TeX code can be safely placed in the body of a macro
+/// definition in generated TeX: its braces must be balanced, and it mustn't
+/// contain comments, macro parameters, control characters, or `^^` notation,
+/// any of which could let it escape the definition.
+pub fn is_self_contained_tex(s: &str) -> bool {
+    let mut depth = 0usize;
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    while chars.next_if(|c| c.is_ascii_alphabetic()).is_some() {}
+                }
+                Some(c) if !c.is_control() => {}
+                _ => return false,
+            },
+            '{' => depth += 1,
+            '}' => {
+                let Some(d) = depth.checked_sub(1) else {
+                    return false;
+                };
+                depth = d;
+            }
+            '%' | '#' => return false,
+            '^' if chars.peek() == Some(&'^') => return false,
+            c if c.is_control() => return false,
+            _ => {}
+        }
+    }
+
+    depth == 0
+}
+
 /// Parse a string of the form `\CSEQ{A}{B}{C}` into the control sequence and an
 /// interator of the individual terms.
 fn parse_cseq_line(s: &str) -> Result<(&str, CseqLineTerms<'_>)> {
@@ -289,4 +1080,213 @@ mod tests {
         assert!(parse_collect("\\t{a}x{b}").is_err());
         assert!(parse_collect("\\t{a}{b}x").is_err());
     }
+
+    #[test]
+    fn write_1() {
+        let m = Metadatum::IndexRef {
+            index: "entries",
+            entry: "a{b}c",
+            flags: IndexRefFlag::NeedsLoc as u8 | IndexRefFlag::NeedsText as u8,
+        };
+        assert_eq!(m.to_string(), "\\iref{entries}{a{b}c}{lt}");
+
+        let m = Metadatum::Output("entry-x.html");
+        assert_eq!(m.to_string(), "\\output{entry-x.html}");
+
+        let m = Metadatum::IndexText {
+            index: "terms",
+            entry: "}{",
+            tex: "x^2",
+            atplain: "a^^b\n",
+        };
+        assert_eq!(m.to_string(), "\\itext{terms}{^^7d^^7b}{x^2}{a^^5e^b^^0a}");
+    }
+
+    #[test]
+    fn reader_1() {
+        let text = "\\output{entry-a.html}\n\
+            \\idef{entries}{a}{#x}\n\
+            \n\
+            \\bogus{a}\n\
+            \\itext{entries}{a}{A very long title that TeX has \n\
+            wrapped}{A very long\n\
+            }\n\
+            \\iref{entries}{a}\n\
+            {lt}\n";
+
+        let records: Vec<_> = PediaTxtReader::new(text.as_bytes()).collect();
+        assert_eq!(records.len(), 5);
+        assert_eq!(
+            records[0],
+            Ok((1, MetadatumBuf::Output("entry-a.html".into())))
+        );
+        assert_eq!(records[2].as_ref().unwrap_err().line, 4);
+        assert_eq!(
+            records[3],
+            Ok((
+                5,
+                MetadatumBuf::IndexText {
+                    index: "entries".into(),
+                    entry: "a".into(),
+                    tex: "A very long title that TeX has wrapped".into(),
+                    atplain: "A very long".into(),
+                }
+            ))
+        );
+        assert_eq!(records[4].as_ref().unwrap().0, 8);
+
+        let mut reader = PediaTxtReader::new(text.as_bytes()).lenient(true);
+        assert_eq!(reader.by_ref().count(), 4);
+        let errors = reader.into_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 4);
+    }
+
+    #[test]
+    fn decode_1() {
+        assert_eq!(decode_escapes("plain"), "plain");
+        assert_eq!(decode_escapes("caf^^e9"), "café");
+        assert_eq!(decode_escapes("a^^^^2014b"), "a\u{2014}b");
+        assert_eq!(decode_escapes("^^^^^^01f600"), "\u{1f600}");
+        assert_eq!(decode_escapes("line^^M"), "line\r");
+        assert_eq!(decode_escapes("^^?"), "\u{7f}");
+        assert_eq!(decode_escapes("^^7b^^7d"), "{}");
+        assert_eq!(decode_escapes("x^2"), "x^2");
+        assert_eq!(decode_escapes("^^E9"), "\u{5}9");
+        assert_eq!(decode_escapes("^^é"), "^^é");
+    }
+
+    #[test]
+    fn at_escape_1() {
+        assert_eq!(at_escape(r"\e{a_b} 100% @home"), "@Be@La@Ub@R 100@P @@home");
+        assert_eq!(at_escape("tab\there"), "tab here");
+        assert_eq!(
+            at_unescape("@Be@La@Ub@R 100@P @@home"),
+            r"\e{a_b} 100% @home"
+        );
+        assert_eq!(at_unescape("a@Z@@@"), "a@Z@@");
+        assert_eq!(
+            at_escape_name("two  words"),
+            Some("two@S@Swords".to_owned())
+        );
+        assert_eq!(at_escape_name("line\r"), None);
+    }
+
+    #[test]
+    fn self_contained_tex_1() {
+        assert!(is_self_contained_tex(r"\dump"));
+        assert!(is_self_contained_tex(r"The {\tt \char`\\} and 100\% \{"));
+        assert!(!is_self_contained_tex(r"a}\def\x{"));
+        assert!(!is_self_contained_tex(r"{unclosed"));
+        assert!(!is_self_contained_tex("50% off"));
+        assert!(!is_self_contained_tex("#1"));
+        assert!(!is_self_contained_tex("^^7d"));
+        assert!(!is_self_contained_tex(r"trailing\"));
+        assert!(!is_self_contained_tex("new\nline"));
+    }
+
+    #[test]
+    fn reader_wrapped() {
+        // As TeX writes a long record: wrapped at 79 characters, with
+        // unprintable characters in `^^` notation.
+        let text = "\\itext{entries}{caf^^e9}{The caf^^e9 and the dash^^^^2014a rather long tit\n\
+            le}{Plain}\n\
+            \\iref{entries}{caf^^e9}{t}\n";
+
+        let records: Vec<_> = PediaTxtReader::new(text.as_bytes())
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (
+                    1,
+                    MetadatumBuf::IndexText {
+                        index: "entries".into(),
+                        entry: "café".into(),
+                        tex: "The café and the dash\u{2014}a rather long title".into(),
+                        atplain: "Plain".into(),
+                    }
+                ),
+                (
+                    3,
+                    MetadatumBuf::IndexRef {
+                        index: "entries".into(),
+                        entry: "café".into(),
+                        flags: IndexRefFlag::NeedsText as u8,
+                    }
+                ),
+            ]
+        );
+    }
+
+    mod round_trip {
+        use super::*;
+        use proptest::prelude::*;
+
+        /// Terms with nested balanced braces, mixed in with arbitrary text
+        /// that exercises the escaping.
+        fn term() -> impl Strategy<Value = String> {
+            let text = "[^{}]{0,8}|[a^{}\\n]{0,8}";
+
+            let nested = text.prop_recursive(4, 32, 4, move |inner| {
+                prop::collection::vec((text, inner), 1..4).prop_map(|parts| {
+                    parts
+                        .into_iter()
+                        .map(|(t, nested)| format!("{t}{{{nested}}}"))
+                        .collect()
+                })
+            });
+
+            prop_oneof![nested, any::<String>()]
+        }
+
+        fn metadatum() -> impl Strategy<Value = MetadatumBuf> {
+            prop_oneof![
+                term().prop_map(MetadatumBuf::Output),
+                (term(), term(), term()).prop_map(|(index, entry, fragment)| {
+                    MetadatumBuf::IndexDef {
+                        index,
+                        entry,
+                        fragment,
+                    }
+                }),
+                (term(), term(), 0u8..4).prop_map(|(index, entry, flags)| {
+                    MetadatumBuf::IndexRef {
+                        index,
+                        entry,
+                        flags,
+                    }
+                }),
+                (term(), term(), term(), term()).prop_map(|(index, entry, tex, atplain)| {
+                    MetadatumBuf::IndexText {
+                        index,
+                        entry,
+                        tex,
+                        atplain,
+                    }
+                }),
+                term().prop_map(MetadatumBuf::Summary),
+                term().prop_map(MetadatumBuf::Category),
+                (term(), term(), term()).prop_map(|(index, alias, target)| {
+                    MetadatumBuf::Alias {
+                        index,
+                        alias,
+                        target,
+                    }
+                }),
+                (term(), term(), term()).prop_map(|(key, tex, atplain)| {
+                    MetadatumBuf::Reference { key, tex, atplain }
+                }),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn parse_write(m in metadatum()) {
+                let line = m.to_string();
+                prop_assert_eq!(MetadatumBuf::parse(&line).unwrap(), m);
+            }
+        }
+    }
 }
@@ -0,0 +1,181 @@
+//! Health checks and Prometheus metrics.
+//!
+//! Every server merges [`router`] into its app to provide:
+//!
+//! - `GET /healthz`: succeeds as long as the process is serving requests
+//! - `GET /readyz`: succeeds if the process's dependencies are usable
+//! - `GET /metrics`: metrics in the Prometheus text exposition format
+//!
+//! The metrics themselves are process-wide statics that the various services
+//! update directly.
+
+use anyhow::Result;
+use axum::{
+    Router,
+    extract::State,
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+};
+use std::{fmt::Write, sync::Arc};
+
+use crate::{nexus::NexusState, repo::RepoState};
+
+/// Documents submitted for compilation through the repo server.
+pub static SUBMISSIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "ttpedia_submissions_total",
+        "Documents submitted for compilation"
+    )
+    .unwrap()
+});
+
+/// Active websocket sync connections to the repo server.
+pub static SYNC_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "ttpedia_sync_connections",
+        "Active websocket sync connections"
+    )
+    .unwrap()
+});
+
+/// Pass-1 requests handled by the nexus.
+pub static PASS1_REQUESTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "ttpedia_nexus_pass1_requests_total",
+        "Pass-1 requests handled by the nexus"
+    )
+    .unwrap()
+});
+
+/// Index references looked up by the nexus.
+pub static INDEX_LOOKUPS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "ttpedia_nexus_index_lookups_total",
+        "Index references looked up by the nexus"
+    )
+    .unwrap()
+});
+
+/// Index references that the nexus could not resolve.
+pub static MISSING_REFS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "ttpedia_nexus_missing_refs_total",
+        "Index references that could not be resolved"
+    )
+    .unwrap()
+});
+
+/// Durations of the TeX compilation passes, labeled by pass number.
+pub static COMPILE_DURATIONS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "ttpedia_compile_duration_seconds",
+        "Durations of TeX compilation passes",
+        &["pass"],
+        vec![0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 60.0, 120.0, 300.0]
+    )
+    .unwrap()
+});
+
+/// Bytes published by the compiler, labeled by destination bucket.
+pub static UPLOAD_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ttpedia_upload_bytes_total",
+        "Bytes of compiled output published",
+        &["bucket"]
+    )
+    .unwrap()
+});
+
+/// Get the histogram tracking the duration of the given compilation pass.
+pub fn compile_duration(pass: &str) -> Histogram {
+    COMPILE_DURATIONS.with_label_values(&[pass])
+}
+
+/// A dependency whose readiness is checked by `GET /readyz`.
+#[derive(Clone)]
+pub enum Component {
+    /// The repo server: its samod repository and job queue.
+    Repo(RepoState),
+
+    /// The nexus: its LMDB database.
+    Nexus(NexusState),
+
+    /// A connection to the Faktory server.
+    Faktory,
+}
+
+impl Component {
+    fn name(&self) -> &'static str {
+        match self {
+            Component::Repo(_) => "repo",
+            Component::Nexus(_) => "nexus",
+            Component::Faktory => "faktory",
+        }
+    }
+
+    async fn check_ready(&self) -> Result<()> {
+        match self {
+            Component::Repo(state) => state.check_ready().await,
+            Component::Nexus(state) => state.check_ready().await,
+            Component::Faktory => {
+                faktory::Client::connect().await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Create the router for the health and metrics endpoints. `components`
+/// lists the dependencies that must be usable for the process to be ready.
+pub fn router(components: Vec<Component>) -> Router {
+    Router::new()
+        .route("/healthz", axum::routing::get(get_healthz_handler))
+        .route("/readyz", axum::routing::get(get_readyz_handler))
+        .route("/metrics", axum::routing::get(get_metrics_handler))
+        .with_state(Arc::new(components))
+}
+
+/// `GET /healthz`: liveness check.
+async fn get_healthz_handler() -> &'static str {
+    "ok\n"
+}
+
+/// `GET /readyz`: readiness check. Reports the status of each component.
+async fn get_readyz_handler(State(components): State<Arc<Vec<Component>>>) -> (StatusCode, String) {
+    let mut status = StatusCode::OK;
+    let mut report = String::new();
+
+    for component in components.iter() {
+        match component.check_ready().await {
+            Ok(()) => writeln!(report, "{}: ok", component.name()).unwrap(),
+            Err(e) => {
+                status = StatusCode::SERVICE_UNAVAILABLE;
+                writeln!(report, "{}: {e:#}", component.name()).unwrap();
+            }
+        }
+    }
+
+    (status, report)
+}
+
+/// `GET /metrics`: Prometheus metrics.
+async fn get_metrics_handler() -> impl IntoResponse {
+    match TextEncoder::new().encode_to_string(&prometheus::gather()) {
+        Ok(text) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            text,
+        ),
+
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain")],
+            e.to_string(),
+        ),
+    }
+}
@@ -0,0 +1,2507 @@
+//! The "nexus" server that is the central gathering point for pedia-wide
+//! data.
+//!
+//! This module provides the HTTP routes and their backing state. The routes are
+//! served standalone by the `ttpedia_nexusserver` binary, or alongside the
+//! other services by `ttpedia_devserver`.
+
+use anyhow::{Result, bail};
+use axum::{
+    Json, Router,
+    extract::{Path, Query},
+    http::StatusCode,
+    response::Redirect,
+};
+use futures::lock::Mutex;
+use lmdb::{Cursor as _, Database, Environment, EnvironmentFlags, Transaction};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    io::Cursor,
+    sync::Arc,
+};
+use tectonic_engine_spx2html::AssetSpecification;
+
+use crate::{
+    NexusBacklink, NexusGetBacklinksResponse, NexusGetBibliographyResponse,
+    NexusGetCategoriesResponse, NexusGetCategoryResponse, NexusGetControlSequenceResponse,
+    NexusGetEntryResponse, NexusGetIndexResponse, NexusGetIndicesResponse,
+    NexusGetReferencesResponse, NexusGetSearchResponse, NexusGetTocResponse, NexusIndexEntry,
+    NexusPostAssetsUploadedRequest, NexusPostAssetsUploadedResponse,
+    NexusPostEntriesUploadedRequest, NexusPostEntriesUploadedResponse, NexusPostPass1Request,
+    NexusPostPass1Response, NexusPostRenameEntryRequest, NexusPostRenameEntryResponse,
+    NexusReference, NexusSearchHit, NexusTocSection,
+    auth::{self, ServiceKey},
+    keys,
+    metadata::{
+        IndexRefFlag, IndexRefFlags, Metadatum, PediaTxtError, PediaTxtReader, at_escape,
+        at_escape_name, at_unescape, is_self_contained_tex,
+    },
+    monitoring, search,
+};
+
+const DB_FORMAT_SERIAL: usize = 1;
+
+struct AssetState {
+    cur_assets: AssetSpecification,
+    cur_seqnum: usize,
+    cur_bucket_key: String,
+    next_proposed_seqnum: usize,
+}
+
+/// The shared state of the nexus server.
+#[derive(Clone)]
+pub struct NexusState {
+    assets: Arc<Mutex<AssetState>>,
+    db: Arc<Environment>,
+    public_data_url: String,
+    key: ServiceKey,
+}
+
+impl NexusState {
+    /// Open the nexus state, storing persistent data in `data_root`.
+    ///
+    /// `public_data_url` is the base URL at which the published data buckets
+    /// can be accessed by clients. `key` is used to authenticate the requests
+    /// of the compiler workers.
+    pub fn new(
+        data_root: &std::path::Path,
+        public_data_url: String,
+        key: ServiceKey,
+    ) -> Result<Self> {
+        let cur_assets = AssetSpecification::default();
+
+        // XXX: recover assets bucket key from persistent storage,
+        // and get assets.json from the bucket. Builders will need to
+        // upload their assets.json! Or we can save it locally.
+        //
+        //let mut assets_save_path = data_root.to_owned();
+        //assets_save_path.push("assets.json");
+        //if let Ok(saved) = std::fs::File::open(&assets_save_path) {
+        //    cur_assets.add_from_saved(saved)?;
+        //}
+
+        let mut db_path = data_root.to_owned();
+        db_path.push(format!("nexus_state_v{DB_FORMAT_SERIAL}.lmdb"));
+        let env = Environment::new()
+            .set_flags(EnvironmentFlags::NO_SUB_DIR)
+            .set_max_dbs(4)
+            .set_map_size(268_435_456)
+            .open(&db_path)?;
+
+        Ok(NexusState {
+            assets: Arc::new(Mutex::new(AssetState {
+                cur_assets,
+                cur_seqnum: 0,
+                cur_bucket_key: "FIXME-get-from-storage".to_owned(),
+                next_proposed_seqnum: 1,
+            })),
+            db: Arc::new(env),
+            public_data_url,
+            key,
+        })
+    }
+
+    /// Check whether the server is ready to handle requests, by verifying that
+    /// the database is usable.
+    pub async fn check_ready(&self) -> Result<()> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let txn = db.begin_ro_txn()?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Flush the database to disk. This should be called after the HTTP server
+    /// has stopped, so that no further writes are in progress.
+    pub fn close(self) -> Result<()> {
+        self.db.sync(true)?;
+        Ok(())
+    }
+}
+
+/// Create the router for the nexus API. All routes live under
+/// `/ttpapi1/nexus`. The `GET` routes are public, while the others require
+/// requests to be signed as described in [`crate::auth`].
+pub fn router(state: NexusState) -> Router {
+    Router::new()
+        .route(
+            "/ttpapi1/nexus/pass1",
+            axum::routing::post(post_pass1_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/assets_uploaded",
+            axum::routing::post(post_assets_uploaded_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/entries_uploaded",
+            axum::routing::post(post_entries_uploaded_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/rename_entry",
+            axum::routing::post(post_rename_entry_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/asset/{key}",
+            axum::routing::get(get_asset_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/entry/{name}",
+            axum::routing::get(get_entry_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/backlinks/{index}/{entry}",
+            axum::routing::get(get_backlinks_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/toc/{entry}",
+            axum::routing::get(get_toc_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/references/{entry}",
+            axum::routing::get(get_references_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/bibliography",
+            axum::routing::get(get_bibliography_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/cs/{name}",
+            axum::routing::get(get_control_sequence_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/indices",
+            axum::routing::get(get_indices_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/index/{index}",
+            axum::routing::get(get_index_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/search",
+            axum::routing::get(get_search_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/categories",
+            axum::routing::get(get_categories_handler),
+        )
+        .route(
+            "/ttpapi1/nexus/category/{name}",
+            axum::routing::get(get_category_handler),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.key.clone(),
+            auth::require_signature,
+        ))
+        .layer(axum::extract::DefaultBodyLimit::max(auth::MAX_BODY_LEN))
+        .with_state(state)
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct IndexKey {
+    pub index: String,
+    pub entry: String,
+}
+
+impl IndexKey {
+    fn new<S1: ToString, S2: ToString>(index: S1, entry: S2) -> Self {
+        IndexKey {
+            index: index.to_string(),
+            entry: entry.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+struct IndexValue {
+    pub output: Option<String>,
+    pub fragment: Option<String>,
+    pub atplain: Option<String>,
+    pub tex: Option<String>,
+}
+
+/// Information about an output file, recorded during pass 1.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct OutputInfo {
+    pub doc_id: String,
+    pub summary: Option<String>,
+    pub categories: Vec<String>,
+}
+
+impl OutputInfo {
+    fn new(doc_id: String) -> Self {
+        OutputInfo {
+            doc_id,
+            ..Default::default()
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut b = self.doc_id.clone().into_bytes();
+        b.push(0);
+        b.extend_from_slice(self.summary.as_deref().unwrap_or_default().as_bytes());
+
+        for category in &self.categories {
+            b.push(0);
+            b.extend_from_slice(category.as_bytes());
+        }
+
+        b
+    }
+
+    fn from_bytes(b: &[u8]) -> Self {
+        let mut fields = b
+            .split(|b| *b == 0)
+            .map(|f| String::from_utf8_lossy(f).into_owned());
+        let doc_id = fields.next().unwrap_or_default();
+        let summary = fields.next().filter(|s| !s.is_empty());
+
+        OutputInfo {
+            doc_id,
+            summary,
+            categories: fields.collect(),
+        }
+    }
+}
+
+/// The searchable content of an entry. The title, fragment and keywords are
+/// recorded during pass 1, and the text once the entry's HTML has been
+/// uploaded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct SearchDoc {
+    pub title: String,
+    pub fragment: String,
+    pub keywords: Vec<String>,
+    pub text: String,
+}
+
+impl SearchDoc {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut b = self.title.clone().into_bytes();
+        b.push(0);
+        b.extend_from_slice(self.fragment.as_bytes());
+        b.push(0);
+        b.extend_from_slice(self.keywords.join("\n").as_bytes());
+        b.push(0);
+        b.extend_from_slice(self.text.as_bytes());
+        b
+    }
+
+    fn from_bytes(b: &[u8]) -> Self {
+        let mut fields = b
+            .splitn(4, |b| *b == 0)
+            .map(|f| String::from_utf8_lossy(f).into_owned());
+        let title = fields.next().unwrap_or_default();
+        let fragment = fields.next().unwrap_or_default();
+        let keywords = fields.next().unwrap_or_default();
+
+        SearchDoc {
+            title,
+            fragment,
+            keywords: keywords.lines().map(|k| k.to_owned()).collect(),
+            text: fields.next().unwrap_or_default(),
+        }
+    }
+
+    /// Get the weighted number of occurrences of each search term in the
+    /// document.
+    fn term_weights(&self) -> HashMap<String, u32> {
+        let mut weights = HashMap::new();
+
+        let sources = std::iter::once((self.title.as_str(), SEARCH_TITLE_WEIGHT))
+            .chain(
+                self.keywords
+                    .iter()
+                    .map(|k| (k.as_str(), SEARCH_KEYWORD_WEIGHT)),
+            )
+            .chain(std::iter::once((self.text.as_str(), 1)));
+
+        for (text, weight) in sources {
+            for term in search::terms(text) {
+                *weights.entry(term).or_default() += weight;
+            }
+        }
+
+        weights
+    }
+}
+
+// Keys in the `index` database start with one of these markers:
+//
+// - `INDEX_DEF_MARKER index \0 entry`: the definition of an index entry, with
+//   the value `output \0 fragment \0 atplain \0 tex`, where `output` is the
+//   path of the output file containing the definition
+// - `OUTPUT_INFO_MARKER output`: an [`OutputInfo`] for the named output file
+// - `CATEGORY_MEMBER_MARKER category \0 output`: present, with an empty value,
+//   if the output file belongs to the category
+// - `ALIAS_MARKER index \0 alias`: an alias for an index entry, with the value
+//   being the name of the target entry
+// - `BACKLINK_MARKER index \0 entry \0 output`: present if the output file
+//   references the index entry, with the value being the ID of the document
+//   that the output comes from. References that could be resolved use the name
+//   of the entry that was found, rather than an alias.
+// - `LINK_MARKER output \0 index \0 entry`: the same information, keyed by the
+//   referencing output so that its links can be replaced when it's recompiled
+// - `SECTIONS_MARKER output`: the names of the entries in the `sections` index
+//   that are defined in the output file, in document order and NUL-separated
+// - `REFERENCE_MARKER key`: the formatted form of the reference with the given
+//   citation key in the `bib` index, with the value `tex \0 atplain`
+// - `DOC_RECORDS_MARKER doc_id`: the keys of the definition, alias, reference
+//   and output info records made by the document, separated by 0xFF bytes
+//   (which never appear in UTF-8), so that they can be removed if it stops
+//   making them
+const INDEX_DEF_MARKER: u8 = 0x80;
+const OUTPUT_INFO_MARKER: u8 = 0x81;
+const CATEGORY_MEMBER_MARKER: u8 = 0x82;
+const ALIAS_MARKER: u8 = 0x83;
+const BACKLINK_MARKER: u8 = 0x84;
+const LINK_MARKER: u8 = 0x85;
+const SECTIONS_MARKER: u8 = 0x86;
+const REFERENCE_MARKER: u8 = 0x87;
+const DOC_RECORDS_MARKER: u8 = 0x88;
+const DOC_RECORDS_SEPARATOR: u8 = 0xFF;
+const MISSING_REF: &[u8] = &[0, 0];
+
+// Keys in the `search` database start with one of these markers:
+//
+// - `SEARCH_DOC_MARKER entry`: a [`SearchDoc`] for the named entry
+// - `SEARCH_TERM_MARKER term \0 entry`: present if the entry contains the
+//   search term, with the value being its weighted number of occurrences as a
+//   little-endian `u32`
+// - `SEARCH_COUNT_MARKER`: the number of `SearchDoc` records, as a
+//   little-endian `u64`, so that queries needn't count them
+const SEARCH_DOC_MARKER: u8 = 0x90;
+const SEARCH_TERM_MARKER: u8 = 0x91;
+const SEARCH_COUNT_MARKER: u8 = 0x92;
+
+/// How much an occurrence of a term in an entry title counts for, relative to
+/// one in the body text.
+const SEARCH_TITLE_WEIGHT: u32 = 5;
+
+/// How much an occurrence of a term in the text of an index definition counts
+/// for, relative to one in the body text.
+const SEARCH_KEYWORD_WEIGHT: u32 = 3;
+
+/// The default number of hits returned by `GET /search`.
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+
+/// The maximum number of hits returned by `GET /search`.
+const MAX_SEARCH_LIMIT: usize = 100;
+
+/// The default number of entries returned by `GET /index/{index}`.
+const DEFAULT_INDEX_PAGE_SIZE: usize = 100;
+
+/// The maximum number of entries returned by `GET /index/{index}`.
+const MAX_INDEX_PAGE_SIZE: usize = 1000;
+
+/// Build a database key from a marker and some NUL-separated parts.
+fn db_key(marker: u8, parts: &[&str]) -> Vec<u8> {
+    let mut key = vec![marker];
+
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            key.push(0);
+        }
+
+        key.extend_from_slice(part.as_bytes());
+    }
+
+    key
+}
+
+/// Iterate over the records in `db` whose keys start with `prefix`, in sorted
+/// order.
+fn iter_prefix<'txn>(
+    cursor: &mut lmdb::RoCursor<'txn>,
+    prefix: Vec<u8>,
+) -> impl Iterator<Item = (&'txn [u8], &'txn [u8])> {
+    let start = prefix.clone();
+    iter_prefix_from(cursor, prefix, start)
+}
+
+/// Like [`iter_prefix`], but starting at the first key that is greater than or
+/// equal to `start`.
+fn iter_prefix_from<'txn>(
+    cursor: &mut lmdb::RoCursor<'txn>,
+    prefix: Vec<u8>,
+    start: Vec<u8>,
+) -> impl Iterator<Item = (&'txn [u8], &'txn [u8])> {
+    let start = std::cmp::max(start, prefix.clone());
+
+    // `iter_from()` panics if there are no keys at or after its starting point,
+    // so check for that first.
+    let found = cursor
+        .get(Some(&start), None, lmdb_sys::MDB_SET_RANGE)
+        .is_ok();
+    let iter = found.then(|| cursor.iter_from(&start));
+
+    iter.into_iter()
+        .flatten()
+        .take_while(move |(k, _)| k.starts_with(&prefix))
+}
+
+/// Look up the definition of an index entry, following an alias if there is no
+/// entry by that name. Returns the name of the entry that was found, along
+/// with its definition.
+fn lookup_index_def<'txn>(
+    txn: &'txn impl Transaction,
+    db: Database,
+    index: &str,
+    entry: &str,
+) -> Option<(String, &'txn [u8])> {
+    if let Ok(def) = txn.get(db, &db_key(INDEX_DEF_MARKER, &[index, entry])) {
+        return Some((entry.to_owned(), def));
+    }
+
+    let target = txn.get(db, &db_key(ALIAS_MARKER, &[index, entry])).ok()?;
+    let target = str::from_utf8(target).ok()?;
+    let def = txn
+        .get(db, &db_key(INDEX_DEF_MARKER, &[index, target]))
+        .ok()?;
+    Some((target.to_owned(), def))
+}
+
+/// Get the name of the entry that an output file holds, if it's an entry page.
+pub fn entry_name_for_output(output: &str) -> Option<&str> {
+    output
+        .strip_prefix("entry-")
+        .and_then(|s| s.strip_suffix(".html"))
+}
+
+/// Get the path of the web page for an output file, relative to the root of
+/// the site. Links are made relative by prefixing this with `\pediaRelTop`,
+/// which each page type defines to be the path from its pages to the root.
+///
+/// Entry pages are served at `e/{entry}`, and `index.html` files by their
+/// directories. Anything else is served at its output path.
+pub fn output_site_path(output: &str) -> String {
+    if let Some(entry) = entry_name_for_output(output) {
+        return format!("e/{entry}");
+    }
+
+    match output.strip_suffix("index.html") {
+        Some(dir) if dir.is_empty() || dir.ends_with('/') => dir.to_owned(),
+        _ => output.to_owned(),
+    }
+}
+
+/// Log an unexpected error in handling a request, and turn it into a response.
+fn internal_error(e: anyhow::Error) -> StatusCode {
+    tracing::error!("failed to handle request: {e:#}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Delete a record from the database, if it exists.
+fn del_if_present(txn: &mut lmdb::RwTransaction, db: Database, key: &[u8]) -> Result<()> {
+    match txn.del(db, &key, None) {
+        Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Delete the links recorded for an output, and the backlinks that mirror them.
+fn delete_output_links(txn: &mut lmdb::RwTransaction, db: Database, output: &str) -> Result<()> {
+    let prefix = db_key(LINK_MARKER, &[output, ""]);
+    let old_links: Vec<Vec<u8>> = {
+        let mut cursor = txn.open_ro_cursor(db)?;
+        iter_prefix(&mut cursor, prefix)
+            .map(|(k, _)| k.to_owned())
+            .collect()
+    };
+
+    for link_key in old_links {
+        let mut parts = link_key[1..].split(|b| *b == 0);
+        let (_, index, entry) = (parts.next(), parts.next(), parts.next());
+        let index = String::from_utf8_lossy(index.unwrap_or_default());
+        let entry = String::from_utf8_lossy(entry.unwrap_or_default());
+
+        for key in [db_key(BACKLINK_MARKER, &[&index, &entry, output]), link_key] {
+            del_if_present(txn, db, &key)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove everything recorded about an output that is no longer produced.
+fn forget_output(
+    txn: &mut lmdb::RwTransaction,
+    db: Database,
+    search_db: Database,
+    output: &str,
+) -> Result<()> {
+    let info_key = db_key(OUTPUT_INFO_MARKER, &[output]);
+
+    if let Ok(info) = txn.get(db, &info_key) {
+        for category in OutputInfo::from_bytes(info).categories {
+            del_if_present(
+                txn,
+                db,
+                &db_key(CATEGORY_MEMBER_MARKER, &[&category, output]),
+            )?;
+        }
+
+        del_if_present(txn, db, &info_key)?;
+    }
+
+    del_if_present(txn, db, &db_key(SECTIONS_MARKER, &[output]))?;
+    delete_output_links(txn, db, output)?;
+
+    if let Some(entry) = entry_name_for_output(output) {
+        delete_search_doc(txn, search_db, entry)?;
+    }
+
+    Ok(())
+}
+
+/// Update the searchable content of an entry, replacing its search terms with
+/// those of the updated content.
+fn update_search_doc(
+    txn: &mut lmdb::RwTransaction,
+    db: Database,
+    entry: &str,
+    update: impl FnOnce(&mut SearchDoc),
+) -> Result<()> {
+    let key = db_key(SEARCH_DOC_MARKER, &[entry]);
+    let mut doc = match txn.get(db, &key) {
+        Ok(b) => SearchDoc::from_bytes(b),
+        Err(lmdb::Error::NotFound) => {
+            adjust_search_doc_count(txn, db, 1)?;
+            SearchDoc::default()
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    for term in doc.term_weights().into_keys() {
+        del_if_present(txn, db, &db_key(SEARCH_TERM_MARKER, &[&term, entry]))?;
+    }
+
+    update(&mut doc);
+
+    for (term, weight) in doc.term_weights() {
+        txn.put(
+            db,
+            &db_key(SEARCH_TERM_MARKER, &[&term, entry]),
+            &weight.to_le_bytes(),
+            Default::default(),
+        )?;
+    }
+
+    txn.put(db, &key, &doc.to_bytes(), Default::default())?;
+    Ok(())
+}
+
+/// Remove an entry from the search index.
+fn delete_search_doc(txn: &mut lmdb::RwTransaction, db: Database, entry: &str) -> Result<()> {
+    let key = db_key(SEARCH_DOC_MARKER, &[entry]);
+    let doc = match txn.get(db, &key) {
+        Ok(b) => SearchDoc::from_bytes(b),
+        Err(lmdb::Error::NotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for term in doc.term_weights().into_keys() {
+        del_if_present(txn, db, &db_key(SEARCH_TERM_MARKER, &[&term, entry]))?;
+    }
+
+    adjust_search_doc_count(txn, db, -1)?;
+    del_if_present(txn, db, &key)
+}
+
+/// Get the number of entries in the search index.
+fn search_doc_count(txn: &impl Transaction, db: Database) -> Result<u64> {
+    match txn.get(db, &[SEARCH_COUNT_MARKER]) {
+        Ok(b) => Ok(b.try_into().map_or(0, u64::from_le_bytes)),
+        Err(lmdb::Error::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Adjust the number of entries in the search index.
+fn adjust_search_doc_count(txn: &mut lmdb::RwTransaction, db: Database, delta: i64) -> Result<()> {
+    let count = search_doc_count(txn, db)?.saturating_add_signed(delta);
+    txn.put(
+        db,
+        &[SEARCH_COUNT_MARKER],
+        &count.to_le_bytes(),
+        Default::default(),
+    )?;
+    Ok(())
+}
+
+/// Rename an entry, leaving behind an alias so that references to the old name
+/// keep working. Returns the IDs of the documents that reference the entry,
+/// which should be recompiled to pick up the new name.
+///
+/// The entry must already be defined under its new name, so this should be
+/// done after the document defining the entry has been updated and recompiled.
+/// Whatever is still recorded under the old name is removed.
+fn rename_entry(env: &Environment, old: &str, new: &str) -> Result<Vec<String>> {
+    const INDEX: &str = "entries";
+
+    if old == new {
+        bail!("the old and new names of the entry are the same");
+    }
+
+    let db = env.create_db(Some("index"), Default::default())?;
+    let search_db = env.create_db(Some("search"), Default::default())?;
+    let mut txn = env.begin_rw_txn()?;
+
+    let old_def_key = db_key(INDEX_DEF_MARKER, &[INDEX, old]);
+    let old_alias_key = db_key(ALIAS_MARKER, &[INDEX, old]);
+
+    if txn
+        .get(db, &db_key(INDEX_DEF_MARKER, &[INDEX, new]))
+        .is_err()
+    {
+        bail!("no entry named `{new}` has been defined");
+    }
+
+    // Recompiling the document that defined the entry will have removed its
+    // definition, but references to it may remain.
+
+    let old_referenced = {
+        let mut cursor = txn.open_ro_cursor(db)?;
+        iter_prefix(&mut cursor, db_key(BACKLINK_MARKER, &[INDEX, old, ""]))
+            .next()
+            .is_some()
+    };
+
+    if txn.get(db, &old_def_key).is_err() && txn.get(db, &old_alias_key).is_err() && !old_referenced
+    {
+        bail!("no entry named `{old}` has been defined or referenced");
+    }
+
+    // Redirect the old name, and any aliases of it, to the new one.
+
+    del_if_present(&mut txn, db, &old_def_key)?;
+    del_if_present(&mut txn, db, &db_key(ALIAS_MARKER, &[INDEX, new]))?;
+    txn.put(db, &old_alias_key, &new, Default::default())?;
+
+    let alias_prefix = db_key(ALIAS_MARKER, &[INDEX, ""]);
+    let stale_aliases: Vec<Vec<u8>> = {
+        let mut cursor = txn.open_ro_cursor(db)?;
+        iter_prefix(&mut cursor, alias_prefix)
+            .filter(|(_, target)| *target == old.as_bytes())
+            .map(|(k, _)| k.to_owned())
+            .collect()
+    };
+
+    for key in stale_aliases {
+        txn.put(db, &key, &new, Default::default())?;
+    }
+
+    // Move the references to the old name over to the new one.
+
+    let backlink_prefix = db_key(BACKLINK_MARKER, &[INDEX, old, ""]);
+    let prefix_len = backlink_prefix.len();
+    let backlinks: Vec<(String, Vec<u8>)> = {
+        let mut cursor = txn.open_ro_cursor(db)?;
+        iter_prefix(&mut cursor, backlink_prefix)
+            .map(|(k, v)| {
+                (
+                    String::from_utf8_lossy(&k[prefix_len..]).into_owned(),
+                    v.to_owned(),
+                )
+            })
+            .collect()
+    };
+
+    for (output, doc_id) in &backlinks {
+        del_if_present(
+            &mut txn,
+            db,
+            &db_key(BACKLINK_MARKER, &[INDEX, old, output]),
+        )?;
+        del_if_present(&mut txn, db, &db_key(LINK_MARKER, &[output, INDEX, old]))?;
+        txn.put(
+            db,
+            &db_key(BACKLINK_MARKER, &[INDEX, new, output]),
+            doc_id,
+            Default::default(),
+        )?;
+        txn.put(
+            db,
+            &db_key(LINK_MARKER, &[output, INDEX, new]),
+            b"",
+            Default::default(),
+        )?;
+    }
+
+    // Forget about the old entry page.
+
+    let old_output = format!("entry-{old}.html");
+    let old_info_key = db_key(OUTPUT_INFO_MARKER, &[&old_output]);
+
+    if let Ok(info) = txn.get(db, &old_info_key) {
+        for category in OutputInfo::from_bytes(info).categories {
+            del_if_present(
+                &mut txn,
+                db,
+                &db_key(CATEGORY_MEMBER_MARKER, &[&category, &old_output]),
+            )?;
+        }
+
+        del_if_present(&mut txn, db, &old_info_key)?;
+    }
+
+    del_if_present(&mut txn, db, &db_key(SECTIONS_MARKER, &[&old_output]))?;
+
+    delete_search_doc(&mut txn, search_db, old)?;
+    txn.commit()?;
+
+    let mut doc_ids: Vec<String> = backlinks
+        .into_iter()
+        .map(|(_, d)| String::from_utf8_lossy(&d).into_owned())
+        .collect();
+    doc_ids.sort();
+    doc_ids.dedup();
+    Ok(doc_ids)
+}
+
+/// Resolve an index reference, appending the TeX needed by the referencing
+/// document to `rrtex`. Returns the name of the entry that the reference
+/// resolved to, or the name that it used if it couldn't be resolved.
+fn resolve_index_ref(
+    txn: &impl Transaction,
+    db: Database,
+    index: &str,
+    entry: &str,
+    flags: IndexRefFlags,
+    rrtex: &mut String,
+) -> String {
+    monitoring::INDEX_LOOKUPS.inc();
+
+    let (target, bvalue) = match lookup_index_def(txn, db, index, entry) {
+        Some(found) => found,
+        None => {
+            monitoring::MISSING_REFS.inc();
+            tracing::debug!(index, entry, "unresolved index reference");
+            (entry.to_owned(), MISSING_REF)
+        }
+    };
+
+    // Names and values are at-escaped so that no definition can break the TeX
+    // that we generate; see `at_escaping.tex` and `crossrefs.tex`.
+
+    let (Some(index_key), Some(entry_key)) = (at_escape_name(index), at_escape_name(entry)) else {
+        tracing::warn!(index, entry, "cannot encode name of index reference");
+        return target;
+    };
+
+    let mut fields = bvalue.split(|b| *b == 0);
+    let output_slice = fields.next();
+    let fragment_slice = fields.next();
+
+    if (flags & IndexRefFlag::NeedsLoc as u8) != 0 {
+        let path = match maybe_slice_to_str_or_default(output_slice, "") {
+            "" => "ENTRYREF".to_owned(),
+            o => output_site_path(o),
+        };
+        let fragment_text = maybe_slice_to_str_or_default(fragment_slice, "");
+        writeln!(
+            rrtex,
+            r"\pediaResolveAt{{{index_key}}}{{{entry_key}}}{{loc}}{{{}}}",
+            at_escape(&format!("{path}{fragment_text}")),
+        )
+        .unwrap();
+    }
+
+    let atplain_slice = fields.next();
+    let tex_slice = fields.next();
+
+    if (flags & IndexRefFlag::NeedsText as u8) != 0 {
+        let atplain_text = maybe_slice_to_str_or_default(atplain_slice, entry);
+        let tex_text = maybe_slice_to_str_or_default(tex_slice, entry);
+
+        // The at-plain text is re-escaped so that any stray characters in it
+        // are escaped too. If the TeX text might not be safe to use, fall back
+        // to the plain text.
+        let plain = at_escape(&at_unescape(atplain_text));
+
+        if is_self_contained_tex(tex_text) {
+            writeln!(
+                rrtex,
+                r"\pediaResolve{{{index_key}}}{{{entry_key}}}{{text tex}}{{{tex_text}}}",
+            )
+            .unwrap();
+        } else {
+            tracing::warn!(index, entry, "TeX text of index entry is unsafe to reuse");
+            writeln!(
+                rrtex,
+                r"\pediaResolveAt{{{index_key}}}{{{entry_key}}}{{text tex}}{{{plain}}}",
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            rrtex,
+            r"\pediaResolve{{{index_key}}}{{{entry_key}}}{{text plain}}{{{plain}}}",
+        )
+        .unwrap();
+    }
+
+    target
+}
+
+fn maybe_slice_to_str_or_default<'a>(b: Option<&'a [u8]>, default: &'a str) -> &'a str {
+    let Some(b) = b else {
+        return default;
+    };
+
+    if b.is_empty() {
+        return default;
+    }
+
+    match str::from_utf8(b) {
+        Ok(s) => s,
+        Err(_) => default,
+    }
+}
+
+/// Record the metadata from a document's first pass in the database, and
+/// resolve its references. Returns the TeX defining the resolved references,
+/// and any problems found in the metadata.
+fn record_pass1_metadata(
+    env: &Environment,
+    doc_id: &str,
+    pedia_txt: &str,
+) -> Result<(String, Vec<PediaTxtError>)> {
+    let db = env.create_db(Some("index"), Default::default())?;
+    let search_db = env.create_db(Some("search"), Default::default())?;
+    let mut txn = env.begin_rw_txn()?;
+
+    let mut current_output = "".to_owned();
+    let mut rrtex = String::new();
+    let mut defs: HashMap<IndexKey, IndexValue> = Default::default();
+    let mut outputs: HashMap<String, OutputInfo> = Default::default();
+    let mut aliases: HashMap<IndexKey, String> = Default::default();
+    let mut links: HashSet<(String, IndexKey)> = Default::default();
+    let mut sections: HashMap<String, Vec<String>> = Default::default();
+    let mut references: HashMap<String, (String, String)> = Default::default();
+    let mut metadata_errors = Vec::new();
+
+    // Bad records are skipped and reported back to the worker, rather
+    // than failing the whole request. An output with an unsafe path is
+    // skipped along with all of the records that belong to it.
+
+    let mut records = Vec::new();
+    let mut skipping_output = false;
+
+    for record in PediaTxtReader::new(pedia_txt.as_bytes()) {
+        match record {
+            Ok((line, m)) => {
+                if let Metadatum::Output(o) = m.as_metadatum() {
+                    skipping_output = match keys::validate_key_path(o) {
+                        Ok(()) => false,
+                        Err(e) => {
+                            let e = PediaTxtError {
+                                line,
+                                message: format!("skipping output: {e:#}"),
+                            };
+                            tracing::warn!("{e}");
+                            metadata_errors.push(e);
+                            true
+                        }
+                    };
+                }
+
+                if !skipping_output {
+                    records.push(m);
+                }
+            }
+
+            Err(e) => {
+                tracing::warn!("{e}");
+                metadata_errors.push(e);
+            }
+        }
+    }
+
+    // First, gather up everything but the references.
+
+    for metadatum in &records {
+        match metadatum.as_metadatum() {
+            // References are resolved once all of the definitions are in.
+            Metadatum::IndexRef { .. } => {}
+
+            Metadatum::IndexDef {
+                index,
+                entry,
+                fragment,
+            } => {
+                let val = defs.entry(IndexKey::new(index, entry)).or_default();
+                val.output = Some(current_output.clone());
+                val.fragment = Some(fragment.to_string());
+
+                if index == "sections" {
+                    sections
+                        .entry(current_output.clone())
+                        .or_default()
+                        .push(entry.to_owned());
+                }
+            }
+
+            Metadatum::IndexText {
+                index,
+                entry,
+                tex,
+                atplain,
+            } => {
+                let val = defs.entry(IndexKey::new(index, entry)).or_default();
+                val.atplain = Some(atplain.to_string());
+                val.tex = Some(tex.to_string());
+            }
+
+            Metadatum::Output(o) => {
+                current_output = o.to_owned();
+                outputs.insert(o.to_owned(), OutputInfo::new(doc_id.to_owned()));
+            }
+
+            Metadatum::Summary(text) => match outputs.get_mut(&current_output) {
+                Some(info) => info.summary = Some(text.to_owned()),
+                None => tracing::warn!("ignoring summary outside of any output"),
+            },
+
+            Metadatum::Category(name) => match outputs.get_mut(&current_output) {
+                Some(info) => {
+                    if !info.categories.iter().any(|c| c == name) {
+                        info.categories.push(name.to_owned());
+                    }
+                }
+                None => tracing::warn!("ignoring category outside of any output"),
+            },
+
+            Metadatum::Alias {
+                index,
+                alias,
+                target,
+            } => {
+                aliases.insert(IndexKey::new(index, alias), target.to_owned());
+            }
+
+            Metadatum::Reference { key, tex, atplain } => {
+                references.insert(key.to_owned(), (tex.to_owned(), atplain.to_owned()));
+            }
+        }
+    }
+
+    // Remove whatever the document recorded last time that it no longer
+    // makes. The outputs that it still produces have their records replaced
+    // below.
+
+    let doc_records_key = db_key(DOC_RECORDS_MARKER, &[doc_id]);
+    let old_records: Vec<Vec<u8>> = match txn.get(db, &doc_records_key) {
+        Ok(b) => b
+            .split(|b| *b == DOC_RECORDS_SEPARATOR)
+            .filter(|k| !k.is_empty())
+            .map(|k| k.to_owned())
+            .collect(),
+        Err(lmdb::Error::NotFound) => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    for key in &old_records {
+        if key[0] == OUTPUT_INFO_MARKER {
+            let output = String::from_utf8_lossy(&key[1..]);
+
+            if !outputs.contains_key(output.as_ref()) {
+                forget_output(&mut txn, db, search_db, &output)?;
+            }
+        } else {
+            del_if_present(&mut txn, db, key)?;
+        }
+    }
+
+    let mut new_records: Vec<Vec<u8>> = Vec::new();
+
+    // Update the searchable titles and keywords of the entries. The body
+    // text gets filled in once the HTML has been uploaded.
+
+    let mut search_docs: HashMap<&str, SearchDoc> = outputs
+        .keys()
+        .filter_map(|o| entry_name_for_output(o))
+        .map(|e| (e, SearchDoc::default()))
+        .collect();
+
+    for (key, value) in &defs {
+        let entry = value.output.as_deref().and_then(entry_name_for_output);
+        let Some(doc) = entry.and_then(|e| search_docs.get_mut(e)) else {
+            continue;
+        };
+
+        if key.index == "entries" && entry == Some(&key.entry) {
+            doc.title = value.atplain.clone().unwrap_or_default();
+            doc.fragment = value.fragment.clone().unwrap_or_default();
+        } else if let Some(atplain) = &value.atplain {
+            doc.keywords.push(at_unescape(atplain).into_owned());
+        }
+    }
+
+    for (entry, new_doc) in search_docs {
+        update_search_doc(&mut txn, search_db, entry, |doc| {
+            doc.title = if new_doc.title.is_empty() {
+                entry.to_owned()
+            } else {
+                new_doc.title
+            };
+            doc.fragment = new_doc.fragment;
+            doc.keywords = new_doc.keywords;
+            doc.keywords.sort();
+        })?;
+    }
+
+    // Record new index definitions in the database
+
+    let new_defs = defs.len();
+
+    for (key, value) in defs.drain() {
+        let mut bkey = vec![INDEX_DEF_MARKER];
+        bkey.append(&mut key.index.into_bytes());
+        bkey.push(0);
+        bkey.append(&mut key.entry.into_bytes());
+
+        let mut bvalue = value.output.unwrap_or_default().into_bytes();
+        bvalue.push(0);
+        bvalue.append(&mut value.fragment.unwrap_or_default().into_bytes());
+        bvalue.push(0);
+        bvalue.append(&mut value.atplain.unwrap_or_default().into_bytes());
+        bvalue.push(0);
+        bvalue.append(&mut value.tex.unwrap_or_default().into_bytes());
+
+        txn.put(db, &bkey, &bvalue, Default::default())?;
+        new_records.push(bkey);
+    }
+
+    // Record the aliases, so that references can go through them as well.
+
+    for (key, target) in aliases.drain() {
+        let key = db_key(ALIAS_MARKER, &[&key.index, &key.entry]);
+        txn.put(db, &key, &target, Default::default())?;
+        new_records.push(key);
+    }
+
+    // Record the formatted forms of the bibliographic references.
+
+    for (key, (tex, atplain)) in references.drain() {
+        let key = db_key(REFERENCE_MARKER, &[&key]);
+        txn.put(db, &key, &[tex, atplain].join("\0"), Default::default())?;
+        new_records.push(key);
+    }
+
+    // Now resolve the references. The definitions and aliases made by this
+    // document are visible through our transaction, so references to them
+    // resolve correctly the first time that it's compiled.
+
+    current_output.clear();
+
+    for metadatum in &records {
+        match metadatum.as_metadatum() {
+            Metadatum::Output(o) => current_output = o.to_owned(),
+
+            Metadatum::IndexRef {
+                index,
+                entry,
+                flags,
+            } => {
+                let target = resolve_index_ref(&txn, db, index, entry, flags, &mut rrtex);
+                links.insert((current_output.clone(), IndexKey::new(index, target)));
+            }
+
+            _ => {}
+        }
+    }
+
+    // Replace the links recorded for the outputs. References made outside
+    // of any output can't be linked back to, so they're not recorded.
+
+    for output in outputs.keys() {
+        delete_output_links(&mut txn, db, output)?;
+    }
+
+    let new_links = links.len();
+
+    for (output, key) in links.drain() {
+        if !outputs.contains_key(&output) {
+            continue;
+        }
+
+        txn.put(
+            db,
+            &db_key(BACKLINK_MARKER, &[&key.index, &key.entry, &output]),
+            &doc_id,
+            Default::default(),
+        )?;
+        txn.put(
+            db,
+            &db_key(LINK_MARKER, &[&output, &key.index, &key.entry]),
+            b"",
+            Default::default(),
+        )?;
+    }
+
+    // Record information about the outputs, replacing whatever we knew
+    // about them before.
+
+    for (output, info) in outputs.drain() {
+        let key = db_key(OUTPUT_INFO_MARKER, &[&output]);
+        let old_info = txn.get(db, &key).ok().map(OutputInfo::from_bytes);
+
+        for category in old_info.map(|i| i.categories).unwrap_or_default() {
+            del_if_present(
+                &mut txn,
+                db,
+                &db_key(CATEGORY_MEMBER_MARKER, &[&category, &output]),
+            )?;
+        }
+
+        for category in &info.categories {
+            txn.put(
+                db,
+                &db_key(CATEGORY_MEMBER_MARKER, &[category, &output]),
+                b"",
+                Default::default(),
+            )?;
+        }
+
+        txn.put(db, &key, &info.to_bytes(), Default::default())?;
+        new_records.push(key);
+
+        let key = db_key(SECTIONS_MARKER, &[&output]);
+
+        match sections.remove(&output) {
+            Some(names) => txn.put(db, &key, &names.join("\0"), Default::default())?,
+            None => del_if_present(&mut txn, db, &key)?,
+        }
+    }
+
+    // Remember what the document recorded, for the next time around.
+
+    if new_records.is_empty() {
+        del_if_present(&mut txn, db, &doc_records_key)?;
+    } else {
+        txn.put(
+            db,
+            &doc_records_key,
+            &new_records.join(&DOC_RECORDS_SEPARATOR),
+            Default::default(),
+        )?;
+    }
+
+    txn.commit()?;
+    tracing::info!(new_defs, new_links, "recorded index definitions");
+
+    Ok((rrtex, metadata_errors))
+}
+
+/// `POST /pass1`: invoked by a TeX compiler worker after its first compilation
+/// pass. We process the set of assets required by this build, and return
+/// information to the worker to allow it to perform the second pass.
+#[tracing::instrument(
+    name = "pass1",
+    skip_all,
+    fields(job_id = %req.job_id, doc_id = %req.doc_id)
+)]
+async fn post_pass1_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Json(req): Json<NexusPostPass1Request>,
+) -> Result<Json<NexusPostPass1Response>, StatusCode> {
+    monitoring::PASS1_REQUESTS.inc();
+
+    // Handle the assets
+
+    let mut assets = state.assets.lock().await;
+
+    let pass1_assets = Cursor::new(req.assets_json.as_bytes());
+    assets
+        .cur_assets
+        .add_from_saved(pass1_assets)
+        .expect("parse and no conflicts");
+
+    let mut pass2_assets: Vec<u8> = Default::default();
+    assets
+        .cur_assets
+        .save(&mut pass2_assets)
+        .expect("save to bytes OK");
+
+    let pass2_assets = String::from_utf8(pass2_assets).expect("saved is string");
+    let mut preserve_assets = None;
+
+    // HACK: tell every build to update assets. We should only do this if they
+    // actually need updating.
+    if true {
+        preserve_assets = Some(assets.next_proposed_seqnum);
+        assets.next_proposed_seqnum += 1;
+    }
+
+    // Handle cross-references
+    //
+    // TBD: do we want to handle definitions after pass 2? Maybe? But if we do
+    // them here, we can avoid having to re-send the `pedia.txt` data after that
+    // pass completes ...
+
+    let doc_id = req.doc_id;
+    let pedia_txt = req.pedia_txt;
+    let dbenv = state.db.clone();
+    let span = tracing::Span::current();
+
+    let (rrtex, metadata_errors) = tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        record_pass1_metadata(&dbenv, &doc_id, &pedia_txt)
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    // All done!
+
+    Ok(Json(NexusPostPass1Response {
+        status: "ok".to_owned(),
+        assets_json: pass2_assets,
+        resolved_reference_tex: rrtex,
+        preserve_assets,
+        metadata_errors,
+    }))
+}
+
+/// `POST /assets_uploaded`: invoked by a TeX compiler worker after it has
+/// uploaded the assets that it generated to the shared bucket, if it was
+/// instructed to do so.
+async fn post_assets_uploaded_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Json(req): Json<NexusPostAssetsUploadedRequest>,
+) -> Result<Json<NexusPostAssetsUploadedResponse>, (StatusCode, String)> {
+    // The key goes into the URLs of the assets, so it had better be a single
+    // well-behaved path segment.
+    if let Err(e) = keys::validate_key_segment(&req.bucket_key) {
+        tracing::warn!("rejecting uploaded assets: {e:#}");
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("invalid bucket key: {e:#}"),
+        ));
+    }
+
+    // We might tell a several builds to upload assets quasi-simultaneously, and
+    // we can't predict the order in which responses will come back. If an early
+    // one comes back late, it's been superseded, and we should just ignore it.
+
+    let mut assets = state.assets.lock().await;
+
+    if req.seq_num > assets.cur_seqnum {
+        assets.cur_bucket_key = req.bucket_key;
+        assets.cur_seqnum = req.seq_num;
+        // TODO: serialize bucket key!!!!
+    }
+
+    Ok(Json(NexusPostAssetsUploadedResponse {}))
+}
+
+/// `POST /entries_uploaded`: invoked by a TeX compiler worker after it has
+/// uploaded the HTML of the entries that it generated. We add their text to the
+/// search index.
+#[tracing::instrument(
+    name = "entries_uploaded",
+    skip_all,
+    fields(job_id = %req.job_id, doc_id = %req.doc_id)
+)]
+async fn post_entries_uploaded_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Json(req): Json<NexusPostEntriesUploadedRequest>,
+) -> Result<Json<NexusPostEntriesUploadedResponse>, StatusCode> {
+    let dbenv = state.db.clone();
+    let span = tracing::Span::current();
+
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let _entered = span.enter();
+        let db = dbenv.create_db(Some("search"), Default::default())?;
+        let mut txn = dbenv.begin_rw_txn()?;
+        let n_entries = req.entries.len();
+
+        for entry in req.entries {
+            update_search_doc(&mut txn, db, &entry.name, |doc| {
+                if doc.title.is_empty() {
+                    doc.title = entry.name.clone();
+                }
+                doc.text = entry.text;
+            })?;
+        }
+
+        txn.commit()?;
+        tracing::info!(n_entries, "indexed entry text");
+        Ok(())
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusPostEntriesUploadedResponse {}))
+}
+
+/// `POST /rename_entry`: rename an entry, redirecting its old name to the new
+/// one. The response lists the documents that should be recompiled so that
+/// their references use the new name.
+#[tracing::instrument(name = "rename_entry", skip_all, fields(old = %req.old, new = %req.new))]
+async fn post_rename_entry_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Json(req): Json<NexusPostRenameEntryRequest>,
+) -> Json<NexusPostRenameEntryResponse> {
+    let db = state.db.clone();
+    let span = tracing::Span::current();
+
+    let result = tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        rename_entry(&db, &req.old, &req.new)
+    })
+    .await
+    .expect("join");
+
+    match result {
+        Ok(referencing_doc_ids) => {
+            tracing::info!(n_docs = referencing_doc_ids.len(), "renamed entry");
+
+            Json(NexusPostRenameEntryResponse {
+                status: "ok".to_owned(),
+                referencing_doc_ids,
+            })
+        }
+
+        Err(e) => {
+            tracing::warn!("failed to rename entry: {e}");
+
+            Json(NexusPostRenameEntryResponse {
+                status: e.to_string(),
+                referencing_doc_ids: Vec::new(),
+            })
+        }
+    }
+}
+
+/// `GET /asset/{key}`: get a shared asset.
+async fn get_asset_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Path(key): Path<String>,
+) -> Result<Redirect, StatusCode> {
+    if keys::validate_key_segment(&key).is_err() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let assets = state.assets.lock().await;
+
+    // TODO/FIXME? Stream out of the bucket rather than redirecting?
+    Ok(Redirect::temporary(&format!(
+        "{}/sharedassets/{}/{}",
+        state.public_data_url, assets.cur_bucket_key, key
+    )))
+}
+
+/// `GET /entry/{name}`: fetch needed info to render an entry page. If `name`
+/// is an alias, the entry that it stands for is returned.
+async fn get_entry_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Path(name): Path<String>,
+) -> Result<Json<NexusGetEntryResponse>, StatusCode> {
+    let db = state.db.clone();
+    let lookup_name = name.clone();
+    let found = tokio::task::spawn_blocking(move || lookup_entry(&db, &lookup_name))
+        .await
+        .expect("join")
+        .map_err(internal_error)?;
+
+    if let Some(resp) = found {
+        return Ok(Json(resp));
+    }
+
+    tracing::warn!("FIXME: fake getentry mapping!");
+
+    let (doc_id, output_name, title) = match name.as_ref() {
+        "dump" => ("gxhZkppeZEXBb7LXnwvHWEuavAd", "dump.html", r"\dump"),
+        "index" => ("tsquNfquQC6eLNYP7ZmgmNkbwXP", "index.html", "Index"),
+        "end" => ("25spacqQwZqMUBMkrCJB1ot1EmGq", "end.html", r"\end"),
+        "message" => ("3huRDC2cWvQhEeFxezP58NWxnMk9", "message.html", r"\message"),
+        "why-tex" => ("3XuSpKARAcsShsAFTZBJKBxwjRsz", "why-tex.html", "Why TeX?"),
+        _ => ("NOT-FOUND", "notfound.html", "Not Found"),
+    };
+
+    let (doc_id, output_name, title) =
+        (doc_id.to_owned(), output_name.to_owned(), title.to_owned());
+
+    Ok(Json(NexusGetEntryResponse {
+        doc_id,
+        output_name,
+        title,
+        name,
+        summary: None,
+        categories: Vec::new(),
+    }))
+}
+
+/// Look up an entry in the database. Returns `None` if it's unknown, or if it
+/// was defined before we started recording the documents that outputs come
+/// from.
+fn lookup_entry(env: &Environment, name: &str) -> Result<Option<NexusGetEntryResponse>> {
+    let Ok(db) = env.open_db(Some("index")) else {
+        return Ok(None);
+    };
+
+    let txn = env.begin_ro_txn()?;
+
+    let Some((canonical, def)) = lookup_index_def(&txn, db, "entries", name) else {
+        return Ok(None);
+    };
+
+    let mut fields = def.split(|b| *b == 0);
+    let output = maybe_slice_to_str_or_default(fields.next(), "");
+    let _fragment = fields.next();
+    let title = maybe_slice_to_str_or_default(fields.next(), &canonical);
+
+    let Some(entry) = entry_name_for_output(output) else {
+        return Ok(None);
+    };
+
+    let Ok(info) = txn.get(db, &db_key(OUTPUT_INFO_MARKER, &[output])) else {
+        return Ok(None);
+    };
+
+    let info = OutputInfo::from_bytes(info);
+
+    Ok(Some(NexusGetEntryResponse {
+        doc_id: info.doc_id,
+        output_name: format!("{entry}.html"),
+        title: title.to_owned(),
+        name: canonical.clone(),
+        summary: info.summary,
+        categories: info.categories,
+    }))
+}
+
+/// `GET /categories`: list the names of all categories that have members.
+async fn get_categories_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+) -> Result<Json<NexusGetCategoriesResponse>, StatusCode> {
+    let db = state.db.clone();
+
+    let categories = tokio::task::spawn_blocking(move || -> Result<Vec<String>> {
+        let Ok(index_db) = db.open_db(Some("index")) else {
+            return Ok(Vec::new());
+        };
+
+        let txn = db.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(index_db)?;
+        let mut categories: Vec<String> = Vec::new();
+
+        for (key, _) in iter_prefix(&mut cursor, vec![CATEGORY_MEMBER_MARKER]) {
+            let name = key[1..].split(|b| *b == 0).next().unwrap_or_default();
+            let name = String::from_utf8_lossy(name);
+
+            // Keys are sorted, so duplicates are adjacent.
+            if categories.last().map(|c| c.as_str()) != Some(&name) {
+                categories.push(name.into_owned());
+            }
+        }
+
+        Ok(categories)
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetCategoriesResponse { categories }))
+}
+
+/// `GET /category/{name}`: list the entries that belong to a category.
+async fn get_category_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Path(name): Path<String>,
+) -> Result<Json<NexusGetCategoryResponse>, StatusCode> {
+    let db = state.db.clone();
+    let category = name.clone();
+
+    let entries = tokio::task::spawn_blocking(move || -> Result<Vec<String>> {
+        let Ok(index_db) = db.open_db(Some("index")) else {
+            return Ok(Vec::new());
+        };
+
+        let txn = db.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(index_db)?;
+        let prefix = db_key(CATEGORY_MEMBER_MARKER, &[&category, ""]);
+        let prefix_len = prefix.len();
+
+        Ok(iter_prefix(&mut cursor, prefix)
+            .filter_map(|(key, _)| {
+                let output = str::from_utf8(&key[prefix_len..]).ok()?;
+                entry_name_for_output(output).map(|e| e.to_owned())
+            })
+            .collect())
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetCategoryResponse { name, entries }))
+}
+
+/// `GET /backlinks/{index}/{entry}`: list the outputs that reference an index
+/// entry. If `entry` is an alias, the references to the entry that it stands
+/// for are returned.
+async fn get_backlinks_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Path((index, entry)): Path<(String, String)>,
+) -> Result<Json<NexusGetBacklinksResponse>, StatusCode> {
+    let db = state.db.clone();
+    let index_name = index.clone();
+
+    let (entry, backlinks) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let Ok(index_db) = db.open_db(Some("index")) else {
+            return Ok((entry, Vec::new()));
+        };
+
+        let txn = db.begin_ro_txn()?;
+        let entry = lookup_index_def(&txn, index_db, &index_name, &entry)
+            .map_or(entry, |(canonical, _)| canonical);
+
+        let mut cursor = txn.open_ro_cursor(index_db)?;
+        let prefix = db_key(BACKLINK_MARKER, &[&index_name, &entry, ""]);
+        let prefix_len = prefix.len();
+
+        let backlinks = iter_prefix(&mut cursor, prefix)
+            .map(|(key, value)| {
+                let output = String::from_utf8_lossy(&key[prefix_len..]).into_owned();
+
+                NexusBacklink {
+                    doc_id: String::from_utf8_lossy(value).into_owned(),
+                    entry: entry_name_for_output(&output).map(|e| e.to_owned()),
+                    output,
+                }
+            })
+            .collect();
+
+        Ok((entry, backlinks))
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetBacklinksResponse {
+        index,
+        entry,
+        backlinks,
+    }))
+}
+
+/// `GET /toc/{entry}`: list the section headings of an entry page, in order.
+/// If `entry` is an alias, the sections of the entry that it stands for are
+/// listed.
+async fn get_toc_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Path(entry): Path<String>,
+) -> Result<Json<NexusGetTocResponse>, StatusCode> {
+    let db = state.db.clone();
+
+    let (entry, sections) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let Ok(index_db) = db.open_db(Some("index")) else {
+            return Ok((entry, Vec::new()));
+        };
+
+        let txn = db.begin_ro_txn()?;
+
+        let Some((canonical, def)) = lookup_index_def(&txn, index_db, "entries", &entry) else {
+            return Ok((entry, Vec::new()));
+        };
+
+        let output = maybe_slice_to_str_or_default(def.split(|b| *b == 0).next(), "");
+
+        let Ok(names) = txn.get(index_db, &db_key(SECTIONS_MARKER, &[output])) else {
+            return Ok((canonical, Vec::new()));
+        };
+
+        let sections = names
+            .split(|b| *b == 0)
+            .filter_map(|name| {
+                let name = str::from_utf8(name).ok()?;
+                let def = txn
+                    .get(index_db, &db_key(INDEX_DEF_MARKER, &["sections", name]))
+                    .ok()?;
+
+                let mut fields = def.split(|b| *b == 0);
+                let output = maybe_slice_to_str_or_default(fields.next(), "");
+                let fragment = maybe_slice_to_str_or_default(fields.next(), "");
+                let atplain = maybe_slice_to_str_or_default(fields.next(), name);
+
+                Some(NexusTocSection {
+                    name: name.to_owned(),
+                    title: at_unescape(atplain).into_owned(),
+                    location: format!("{}{fragment}", output_site_path(output)),
+                })
+            })
+            .collect();
+
+        Ok((canonical, sections))
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetTocResponse { entry, sections }))
+}
+
+/// Look up an entry in the `bib` index, along with its formatted reference.
+fn lookup_reference(txn: &impl Transaction, db: Database, key: &str) -> Option<NexusReference> {
+    let def = txn.get(db, &db_key(INDEX_DEF_MARKER, &["bib", key])).ok()?;
+
+    let mut fields = def.split(|b| *b == 0);
+    let output = maybe_slice_to_str_or_default(fields.next(), "");
+    let fragment = maybe_slice_to_str_or_default(fields.next(), "");
+    let label = maybe_slice_to_str_or_default(fields.next(), key);
+
+    let formatted = txn.get(db, &db_key(REFERENCE_MARKER, &[key])).ok();
+    let atplain =
+        maybe_slice_to_str_or_default(formatted.and_then(|f| f.split(|b| *b == 0).nth(1)), "");
+
+    let location = if output.is_empty() {
+        String::new()
+    } else {
+        format!("{}{fragment}", output_site_path(output))
+    };
+
+    Some(NexusReference {
+        key: key.to_owned(),
+        label: at_unescape(label).into_owned(),
+        reference: at_unescape(atplain).into_owned(),
+        location,
+    })
+}
+
+/// `GET /references/{entry}`: list the references cited by an entry page. If
+/// `entry` is an alias, the citations of the entry that it stands for are
+/// listed.
+async fn get_references_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Path(entry): Path<String>,
+) -> Result<Json<NexusGetReferencesResponse>, StatusCode> {
+    let db = state.db.clone();
+
+    let (entry, references) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let Ok(index_db) = db.open_db(Some("index")) else {
+            return Ok((entry, Vec::new()));
+        };
+
+        let txn = db.begin_ro_txn()?;
+
+        let Some((canonical, def)) = lookup_index_def(&txn, index_db, "entries", &entry) else {
+            return Ok((entry, Vec::new()));
+        };
+
+        let output = maybe_slice_to_str_or_default(def.split(|b| *b == 0).next(), "");
+        let mut cursor = txn.open_ro_cursor(index_db)?;
+        let prefix = db_key(LINK_MARKER, &[output, "bib", ""]);
+        let prefix_len = prefix.len();
+
+        let references = iter_prefix(&mut cursor, prefix)
+            .filter_map(|(key, _)| {
+                let key = str::from_utf8(&key[prefix_len..]).ok()?;
+                lookup_reference(&txn, index_db, key)
+            })
+            .collect();
+
+        Ok((canonical, references))
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetReferencesResponse { entry, references }))
+}
+
+/// `GET /bibliography`: list all of the references that can be cited.
+async fn get_bibliography_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+) -> Result<Json<NexusGetBibliographyResponse>, StatusCode> {
+    let db = state.db.clone();
+
+    let references = tokio::task::spawn_blocking(move || -> Result<Vec<NexusReference>> {
+        let Ok(index_db) = db.open_db(Some("index")) else {
+            return Ok(Vec::new());
+        };
+
+        let txn = db.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(index_db)?;
+        let prefix = db_key(INDEX_DEF_MARKER, &["bib", ""]);
+        let prefix_len = prefix.len();
+
+        Ok(iter_prefix(&mut cursor, prefix)
+            .filter_map(|(key, _)| {
+                let key = str::from_utf8(&key[prefix_len..]).ok()?;
+                lookup_reference(&txn, index_db, key)
+            })
+            .collect())
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetBibliographyResponse { references }))
+}
+
+/// `GET /cs/{name}`: find the entry documenting a TeX control sequence. The
+/// name may be given with or without its leading backslash.
+async fn get_control_sequence_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Path(name): Path<String>,
+) -> Result<Json<NexusGetControlSequenceResponse>, StatusCode> {
+    let db = state.db.clone();
+    let name = name.strip_prefix('\\').unwrap_or(&name).to_owned();
+    let control_sequence = format!("\\{name}");
+
+    let (entry, location) = tokio::task::spawn_blocking(move || -> Result<_> {
+        // Names in the `cs` index are escaped in the same way as names in the
+        // resolved-reference TeX.
+        let Some(index_name) = at_escape_name(&name) else {
+            return Ok((None, String::new()));
+        };
+
+        let Ok(index_db) = db.open_db(Some("index")) else {
+            return Ok((None, String::new()));
+        };
+
+        let txn = db.begin_ro_txn()?;
+
+        let Some((_, def)) = lookup_index_def(&txn, index_db, "cs", &index_name) else {
+            return Ok((None, String::new()));
+        };
+
+        let mut fields = def.split(|b| *b == 0);
+        let output = maybe_slice_to_str_or_default(fields.next(), "");
+        let fragment = maybe_slice_to_str_or_default(fields.next(), "");
+
+        if output.is_empty() {
+            return Ok((None, String::new()));
+        }
+
+        Ok((
+            entry_name_for_output(output).map(|e| e.to_owned()),
+            format!("{}{fragment}", output_site_path(output)),
+        ))
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetControlSequenceResponse {
+        control_sequence,
+        entry,
+        location,
+    }))
+}
+
+/// `GET /indices`: list the names of all indices that have definitions.
+async fn get_indices_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+) -> Result<Json<NexusGetIndicesResponse>, StatusCode> {
+    let db = state.db.clone();
+
+    let indices = tokio::task::spawn_blocking(move || -> Result<Vec<String>> {
+        let Ok(index_db) = db.open_db(Some("index")) else {
+            return Ok(Vec::new());
+        };
+
+        let txn = db.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(index_db)?;
+        let mut indices: Vec<String> = Vec::new();
+
+        for (key, _) in iter_prefix(&mut cursor, vec![INDEX_DEF_MARKER]) {
+            let name = key[1..].split(|b| *b == 0).next().unwrap_or_default();
+            let name = String::from_utf8_lossy(name);
+
+            // Keys are sorted, so duplicates are adjacent.
+            if indices.last().map(|c| c.as_str()) != Some(&name) {
+                indices.push(name.into_owned());
+            }
+        }
+
+        Ok(indices)
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetIndicesResponse { indices }))
+}
+
+#[derive(Debug, Deserialize)]
+struct GetIndexQuery {
+    /// Only return entries whose names start with this string.
+    #[serde(default)]
+    prefix: String,
+
+    /// Only return entries whose names sort after this one. Used for paging.
+    after: Option<String>,
+
+    /// The maximum number of entries to return.
+    limit: Option<usize>,
+}
+
+/// `GET /index/{index}`: list the entries of an index, in sorted order, one
+/// page at a time. The `next_after` field of the response gives the value of
+/// the `after` parameter to use to get the next page.
+async fn get_index_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Path(index): Path<String>,
+    Query(query): Query<GetIndexQuery>,
+) -> Result<Json<NexusGetIndexResponse>, StatusCode> {
+    let db = state.db.clone();
+    let index_name = index.clone();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_INDEX_PAGE_SIZE)
+        .clamp(1, MAX_INDEX_PAGE_SIZE);
+
+    let (entries, next_after) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let Ok(index_db) = db.open_db(Some("index")) else {
+            return Ok((Vec::new(), None));
+        };
+
+        let txn = db.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(index_db)?;
+        let name_offset = db_key(INDEX_DEF_MARKER, &[&index_name, ""]).len();
+        let prefix = db_key(INDEX_DEF_MARKER, &[&index_name, &query.prefix]);
+
+        let after = query
+            .after
+            .as_ref()
+            .map(|a| db_key(INDEX_DEF_MARKER, &[&index_name, a]));
+        let start = after.clone().unwrap_or_else(|| prefix.clone());
+
+        let mut entries = Vec::new();
+        let mut next_after = None;
+
+        for (key, value) in iter_prefix_from(&mut cursor, prefix, start) {
+            if after.as_deref() == Some(key) {
+                continue;
+            }
+
+            if entries.len() == limit {
+                next_after = entries.last().map(|e: &NexusIndexEntry| e.name.clone());
+                break;
+            }
+
+            let mut fields = value.split(|b| *b == 0);
+            let output = maybe_slice_to_str_or_default(fields.next(), "");
+            let fragment = maybe_slice_to_str_or_default(fields.next(), "");
+            let path = if output.is_empty() {
+                String::new()
+            } else {
+                output_site_path(output)
+            };
+
+            let doc_id = txn
+                .get(index_db, &db_key(OUTPUT_INFO_MARKER, &[output]))
+                .ok()
+                .map(|info| OutputInfo::from_bytes(info).doc_id);
+
+            entries.push(NexusIndexEntry {
+                name: String::from_utf8_lossy(&key[name_offset..]).into_owned(),
+                location: format!("{path}{fragment}"),
+                atplain: maybe_slice_to_str_or_default(fields.next(), "").to_owned(),
+                tex: maybe_slice_to_str_or_default(fields.next(), "").to_owned(),
+                doc_id,
+            });
+        }
+
+        Ok((entries, next_after))
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetIndexResponse {
+        index,
+        entries,
+        next_after,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct GetSearchQuery {
+    /// The search query.
+    #[serde(default)]
+    q: String,
+
+    /// The maximum number of hits to return.
+    limit: Option<usize>,
+}
+
+/// `GET /search`: full-text search over the compiled entries. Hits are ranked
+/// by a TF-IDF score, with matches in titles and index terms counting for more
+/// than matches in the body text.
+async fn get_search_handler(
+    axum::extract::State(state): axum::extract::State<NexusState>,
+    Query(query): Query<GetSearchQuery>,
+) -> Result<Json<NexusGetSearchResponse>, StatusCode> {
+    let db = state.db.clone();
+    let q = query.q.clone();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    let hits = tokio::task::spawn_blocking(move || -> Result<Vec<NexusSearchHit>> {
+        let mut query_terms: Vec<String> = search::terms(&q).collect();
+        query_terms.sort();
+        query_terms.dedup();
+
+        if query_terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let Ok(search_db) = db.open_db(Some("search")) else {
+            return Ok(Vec::new());
+        };
+
+        let txn = db.begin_ro_txn()?;
+        let n_docs = search_doc_count(&txn, search_db)? as f64;
+        let mut cursor = txn.open_ro_cursor(search_db)?;
+        let mut scores: HashMap<String, f64> = HashMap::new();
+
+        for term in &query_terms {
+            let prefix = db_key(SEARCH_TERM_MARKER, &[term, ""]);
+            let prefix_len = prefix.len();
+            let postings: Vec<_> = iter_prefix(&mut cursor, prefix).collect();
+            let idf = (1. + n_docs / postings.len().max(1) as f64).ln();
+
+            for (key, value) in postings {
+                let entry = String::from_utf8_lossy(&key[prefix_len..]).into_owned();
+                let weight = value.try_into().map_or(1, u32::from_le_bytes);
+                *scores.entry(entry).or_default() += (1. + (weight as f64).ln()) * idf;
+            }
+        }
+
+        let mut ranked: Vec<_> = scores.into_iter().collect();
+        ranked.sort_by(|(e1, s1), (e2, s2)| s2.total_cmp(s1).then_with(|| e1.cmp(e2)));
+        ranked.truncate(limit);
+
+        let mut hits = Vec::with_capacity(ranked.len());
+
+        for (name, _) in ranked {
+            let doc = match txn.get(search_db, &db_key(SEARCH_DOC_MARKER, &[&name])) {
+                Ok(b) => SearchDoc::from_bytes(b),
+                Err(lmdb::Error::NotFound) => SearchDoc::default(),
+                Err(e) => return Err(e.into()),
+            };
+
+            let snippet_source = if doc.text.is_empty() {
+                doc.keywords.join("; ")
+            } else {
+                doc.text
+            };
+
+            hits.push(NexusSearchHit {
+                snippet: search::snippet(&snippet_source, &query_terms),
+                title: doc.title,
+                fragment: doc.fragment,
+                name,
+            });
+        }
+
+        Ok(hits)
+    })
+    .await
+    .expect("join")
+    .map_err(internal_error)?;
+
+    Ok(Json(NexusGetSearchResponse {
+        query: query.q,
+        hits,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+
+    fn test_state() -> (tempfile::TempDir, NexusState) {
+        let dir = tempfile::tempdir().unwrap();
+        let key = ServiceKey::new("0123456789abcdef").unwrap();
+        let state = NexusState::new(dir.path(), "http://data.example".to_owned(), key).unwrap();
+        (dir, state)
+    }
+
+    #[test]
+    fn site_paths_1() {
+        assert_eq!(output_site_path("entry-x.html"), "e/x");
+        assert_eq!(
+            output_site_path("explain/why-tex/index.html"),
+            "explain/why-tex/"
+        );
+        assert_eq!(output_site_path("index.html"), "");
+        assert_eq!(
+            output_site_path("explain/why-tex/notes.html"),
+            "explain/why-tex/notes.html"
+        );
+        assert_eq!(output_site_path("myindex.html"), "myindex.html");
+    }
+
+    #[test]
+    fn pass1_self_refs() {
+        let (_dir, state) = test_state();
+
+        // A brand-new document referring to its own entry, directly and
+        // through an alias, before anything else has been recorded.
+        let pedia_txt = "\\output{entry-x.html}\n\
+            \\idef{entries}{x}{#def}\n\
+            \\itext{entries}{x}{The X}{The X}\n\
+            \\alias{entries}{ex}{x}\n\
+            \\iref{entries}{x}{lt}\n\
+            \\iref{entries}{ex}{l}\n\
+            \\iref{entries}{nowhere}{l}\n";
+
+        let (rrtex, errors) = record_pass1_metadata(&state.db, "doc1", pedia_txt).unwrap();
+        assert!(errors.is_empty());
+
+        let loc = at_escape("e/x#def");
+        assert!(
+            rrtex.contains(&format!(r"\pediaResolveAt{{entries}}{{x}}{{loc}}{{{loc}}}")),
+            "{rrtex}"
+        );
+        assert!(
+            rrtex.contains(&format!(
+                r"\pediaResolveAt{{entries}}{{ex}}{{loc}}{{{loc}}}"
+            )),
+            "{rrtex}"
+        );
+        assert!(
+            rrtex.contains(r"\pediaResolve{entries}{x}{text tex}{The X}"),
+            "{rrtex}"
+        );
+
+        // Only the reference to the undefined entry is left unresolved.
+        assert_eq!(rrtex.matches("ENTRYREF").count(), 1, "{rrtex}");
+    }
+
+    #[test]
+    fn rename_1() {
+        let (_dir, state) = test_state();
+        let env = &state.db;
+
+        record_pass1_metadata(
+            env,
+            "doc1",
+            "\\output{entry-old.html}\n\\idef{entries}{old}{#a}\n",
+        )
+        .unwrap();
+        record_pass1_metadata(
+            env,
+            "doc2",
+            "\\output{entry-other.html}\n\
+            \\idef{entries}{other}{#b}\n\
+            \\alias{entries}{o}{old}\n\
+            \\iref{entries}{old}{l}\n",
+        )
+        .unwrap();
+
+        // The defining document is updated to use the new name, then the
+        // rename is requested.
+        record_pass1_metadata(
+            env,
+            "doc1",
+            "\\output{entry-new.html}\n\\idef{entries}{new}{#a}\n",
+        )
+        .unwrap();
+        assert!(rename_entry(env, "new", "new").is_err());
+        assert!(rename_entry(env, "old", "nonexistent").is_err());
+        assert_eq!(rename_entry(env, "old", "new").unwrap(), vec!["doc2"]);
+
+        let db = env.open_db(Some("index")).unwrap();
+        let txn = env.begin_ro_txn().unwrap();
+
+        let (target, def) = lookup_index_def(&txn, db, "entries", "old").unwrap();
+        assert_eq!(target, "new");
+        assert!(def.starts_with(b"entry-new.html\0#a"));
+
+        assert_eq!(
+            txn.get(
+                db,
+                &db_key(BACKLINK_MARKER, &["entries", "new", "entry-other.html"])
+            ),
+            Ok(&b"doc2"[..])
+        );
+        assert!(
+            txn.get(
+                db,
+                &db_key(BACKLINK_MARKER, &["entries", "old", "entry-other.html"])
+            )
+            .is_err()
+        );
+
+        assert_eq!(
+            txn.get(db, &db_key(ALIAS_MARKER, &["entries", "o"])),
+            Ok(&b"new"[..])
+        );
+        assert_eq!(lookup_index_def(&txn, db, "entries", "o").unwrap().0, "new");
+    }
+
+    #[test]
+    fn search_count_1() {
+        let (_dir, state) = test_state();
+        let env = &state.db;
+        let db = env.create_db(Some("search"), Default::default()).unwrap();
+
+        let mut txn = env.begin_rw_txn().unwrap();
+        update_search_doc(&mut txn, db, "a", |d| d.title = "A".to_owned()).unwrap();
+        update_search_doc(&mut txn, db, "b", |d| d.title = "B".to_owned()).unwrap();
+        update_search_doc(&mut txn, db, "a", |d| d.text = "again".to_owned()).unwrap();
+        delete_search_doc(&mut txn, db, "b").unwrap();
+        delete_search_doc(&mut txn, db, "nonexistent").unwrap();
+        assert_eq!(search_doc_count(&txn, db).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn resubmit_1() {
+        let (_dir, state) = test_state();
+
+        let pedia_txt = "\\output{entry-refs.html}\n\
+            \\idef{entries}{refs}{}\n\
+            \\idef{bib}{knuth84}{#bib-knuth84}\n\
+            \\reference{knuth84}{D. Knuth}{D. Knuth}\n\
+            \\alias{entries}{references}{refs}\n\
+            \\output{entry-old.html}\n\
+            \\idef{entries}{old}{}\n\
+            \\category{misc}\n\
+            \\iref{bib}{knuth84}{l}\n";
+        record_pass1_metadata(&state.db, "doc1", pedia_txt).unwrap();
+
+        let bib = get_bibliography_handler(State(state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(bib.references.len(), 1);
+        assert_eq!(bib.references[0].reference, "D. Knuth");
+
+        // The document drops its reference, its alias and one of its outputs.
+        record_pass1_metadata(
+            &state.db,
+            "doc1",
+            "\\output{entry-refs.html}\n\\idef{entries}{refs}{}\n",
+        )
+        .unwrap();
+
+        let bib = get_bibliography_handler(State(state.clone()))
+            .await
+            .unwrap();
+        assert!(bib.references.is_empty());
+
+        let refs = get_references_handler(State(state.clone()), Path("old".to_owned()))
+            .await
+            .unwrap();
+        assert!(refs.references.is_empty());
+
+        let cats = get_categories_handler(State(state.clone())).await.unwrap();
+        assert!(cats.categories.is_empty());
+
+        let db = state.db.open_db(Some("index")).unwrap();
+        let txn = state.db.begin_ro_txn().unwrap();
+        assert!(lookup_index_def(&txn, db, "entries", "refs").is_some());
+        assert!(lookup_index_def(&txn, db, "entries", "old").is_none());
+        assert!(lookup_index_def(&txn, db, "entries", "references").is_none());
+        assert!(
+            txn.get(db, &db_key(OUTPUT_INFO_MARKER, &["entry-old.html"]))
+                .is_err()
+        );
+        assert!(
+            txn.get(
+                db,
+                &db_key(BACKLINK_MARKER, &["bib", "knuth84", "entry-old.html"])
+            )
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn index_listing_1() {
+        let (_dir, state) = test_state();
+
+        for name in ["apple", "apricot", "banana", "cherry", "avocado"] {
+            let pedia_txt = format!(
+                "\\output{{entry-{name}.html}}\n\
+                \\idef{{entries}}{{{name}}}{{}}\n\
+                \\itext{{entries}}{{{name}}}{{T{name}}}{{P{name}}}\n\
+                \\idef{{terms}}{{t-{name}}}{{#x}}\n"
+            );
+            record_pass1_metadata(&state.db, name, &pedia_txt).unwrap();
+        }
+
+        let indices = get_indices_handler(State(state.clone())).await.unwrap();
+        assert_eq!(indices.indices, vec!["entries", "terms"]);
+
+        let query = |prefix: &str, after: Option<&str>, limit| {
+            Query(GetIndexQuery {
+                prefix: prefix.to_owned(),
+                after: after.map(str::to_owned),
+                limit,
+            })
+        };
+
+        let page = get_index_handler(
+            State(state.clone()),
+            Path("entries".to_owned()),
+            query("", None, Some(2)),
+        )
+        .await
+        .unwrap();
+        let names: Vec<_> = page.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["apple", "apricot"]);
+        assert_eq!(page.entries[0].location, "e/apple");
+        assert_eq!(page.entries[0].tex, "Tapple");
+        assert_eq!(page.entries[0].atplain, "Papple");
+        assert_eq!(page.entries[0].doc_id.as_deref(), Some("apple"));
+        assert_eq!(page.next_after.as_deref(), Some("apricot"));
+
+        // The second page picks up where the first left off.
+        let page = get_index_handler(
+            State(state.clone()),
+            Path("entries".to_owned()),
+            query("", page.next_after.as_deref(), Some(2)),
+        )
+        .await
+        .unwrap();
+        let names: Vec<_> = page.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["avocado", "banana"]);
+        assert_eq!(page.next_after.as_deref(), Some("banana"));
+
+        let page = get_index_handler(
+            State(state.clone()),
+            Path("entries".to_owned()),
+            query("a", Some("apple"), None),
+        )
+        .await
+        .unwrap();
+        let names: Vec<_> = page.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["apricot", "avocado"]);
+        assert_eq!(page.next_after, None);
+
+        let page = get_index_handler(
+            State(state.clone()),
+            Path("terms".to_owned()),
+            query("t-c", None, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.entries[0].location, "e/cherry#x");
+
+        let page = get_index_handler(
+            State(state.clone()),
+            Path("nonexistent".to_owned()),
+            query("", None, None),
+        )
+        .await
+        .unwrap();
+        assert!(page.entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn backlinks_toc_1() {
+        let (_dir, state) = test_state();
+
+        record_pass1_metadata(
+            &state.db,
+            "doc1",
+            "\\output{entry-foo.html}\n\
+            \\idef{entries}{foo}{}\n\
+            \\idef{sections}{foo/zeta}{#zeta}\n\
+            \\itext{sections}{foo/zeta}{Zeta \\TeX}{Zeta @@ home}\n\
+            \\idef{sections}{foo/alpha}{#alpha}\n\
+            \\alias{entries}{fu}{foo}\n",
+        )
+        .unwrap();
+        record_pass1_metadata(
+            &state.db,
+            "doc2",
+            "\\iref{entries}{foo}{l}\n\
+            \\output{entry-bar.html}\n\
+            \\iref{entries}{fu}{l}\n\
+            \\iref{entries}{foo}{t}\n\
+            \\output{other.html}\n\
+            \\iref{entries}{foo}{l}\n\
+            \\iref{entries}{nope}{l}\n",
+        )
+        .unwrap();
+
+        let links = get_backlinks_handler(
+            State(state.clone()),
+            Path(("entries".to_owned(), "fu".to_owned())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(links.entry, "foo");
+        assert_eq!(
+            links.backlinks,
+            vec![
+                NexusBacklink {
+                    doc_id: "doc2".to_owned(),
+                    output: "entry-bar.html".to_owned(),
+                    entry: Some("bar".to_owned()),
+                },
+                NexusBacklink {
+                    doc_id: "doc2".to_owned(),
+                    output: "other.html".to_owned(),
+                    entry: None,
+                },
+            ]
+        );
+
+        let links = get_backlinks_handler(
+            State(state.clone()),
+            Path(("entries".to_owned(), "nope".to_owned())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(links.backlinks.len(), 1);
+        assert_eq!(links.backlinks[0].output, "other.html");
+
+        let toc = get_toc_handler(State(state.clone()), Path("fu".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(toc.entry, "foo");
+        assert_eq!(
+            toc.sections,
+            vec![
+                NexusTocSection {
+                    name: "foo/zeta".to_owned(),
+                    title: "Zeta @ home".to_owned(),
+                    location: "e/foo#zeta".to_owned(),
+                },
+                NexusTocSection {
+                    name: "foo/alpha".to_owned(),
+                    title: "foo/alpha".to_owned(),
+                    location: "e/foo#alpha".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn references_1() {
+        let (_dir, state) = test_state();
+
+        record_pass1_metadata(
+            &state.db,
+            "doc1",
+            "\\output{entry-refs.html}\n\
+            \\idef{entries}{refs}{}\n\
+            \\idef{bib}{knuth84}{#bib-knuth84}\n\
+            \\itext{bib}{knuth84}{Knuth 1984}{Knuth 1984}\n\
+            \\reference{knuth84}{D. Knuth, \\textit{The \\TeX book}}{D. Knuth, The @BTeX@Rbook}\n\
+            \\idef{bib}{lamport}{#bib-lamport}\n\
+            \\output{entry-a.html}\n\
+            \\idef{entries}{a}{}\n\
+            \\iref{bib}{knuth84}{lt}\n",
+        )
+        .unwrap();
+
+        let knuth = NexusReference {
+            key: "knuth84".to_owned(),
+            label: "Knuth 1984".to_owned(),
+            reference: "D. Knuth, The \\TeX}book".to_owned(),
+            location: "e/refs#bib-knuth84".to_owned(),
+        };
+
+        let refs = get_references_handler(State(state.clone()), Path("a".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(refs.entry, "a");
+        assert_eq!(refs.references, vec![knuth.clone()]);
+
+        let bib = get_bibliography_handler(State(state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(bib.references.len(), 2);
+        assert_eq!(bib.references[0], knuth);
+        assert_eq!(bib.references[1].key, "lamport");
+        assert_eq!(bib.references[1].label, "lamport");
+        assert_eq!(bib.references[1].reference, "");
+    }
+
+    #[tokio::test]
+    async fn control_sequences_1() {
+        let (_dir, state) = test_state();
+
+        record_pass1_metadata(
+            &state.db,
+            "doc1",
+            "\\output{entry-expandafter.html}\n\
+            \\idef{entries}{expandafter}{}\n\
+            \\idef{cs}{expandafter}{}\n\
+            \\itext{cs}{expandafter}{\\cs{expandafter}}{@Bexpandafter}\n\
+            \\idef{cs}{@L}{#brace}\n",
+        )
+        .unwrap();
+
+        let cs =
+            get_control_sequence_handler(State(state.clone()), Path("\\expandafter".to_owned()))
+                .await
+                .unwrap();
+        assert_eq!(cs.control_sequence, "\\expandafter");
+        assert_eq!(cs.entry.as_deref(), Some("expandafter"));
+        assert_eq!(cs.location, "e/expandafter");
+
+        let cs = get_control_sequence_handler(State(state.clone()), Path("{".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(cs.location, "e/expandafter#brace");
+
+        let cs = get_control_sequence_handler(State(state.clone()), Path("relax".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(cs.entry, None);
+        assert_eq!(cs.location, "");
+    }
+
+    #[tokio::test]
+    async fn categories_1() {
+        let (_dir, state) = test_state();
+
+        record_pass1_metadata(
+            &state.db,
+            "doc1",
+            "\\output{entry-foo.html}\n\
+            \\idef{entries}{foo}{}\n\
+            \\itext{entries}{foo}{\\foo}{Foo}\n\
+            \\summary{About foo}\n\
+            \\category{prims}\n\
+            \\category{misc}\n\
+            \\alias{entries}{fu}{foo}\n",
+        )
+        .unwrap();
+
+        let entry = get_entry_handler(State(state.clone()), Path("fu".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(entry.name, "foo");
+        assert_eq!(entry.doc_id, "doc1");
+        assert_eq!(entry.summary.as_deref(), Some("About foo"));
+
+        let cats = get_categories_handler(State(state.clone())).await.unwrap();
+        assert_eq!(cats.categories, vec!["misc", "prims"]);
+
+        let cat = get_category_handler(State(state.clone()), Path("prims".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(cat.entries, vec!["foo"]);
+
+        record_pass1_metadata(
+            &state.db,
+            "doc1",
+            "\\output{entry-foo.html}\n\
+            \\idef{entries}{foo}{}\n\
+            \\category{misc}\n",
+        )
+        .unwrap();
+
+        let cats = get_categories_handler(State(state.clone())).await.unwrap();
+        assert_eq!(cats.categories, vec!["misc"]);
+    }
+}
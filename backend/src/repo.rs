@@ -0,0 +1,197 @@
+//! The Automerge repository server that is our ultimate document storage
+//! backend.
+//!
+//! Implementation originally cribbed from the Samod JS compatibility test
+//! files. The routes are served standalone by the `ttpedia_reposerver` binary,
+//! or alongside the other services by `ttpedia_devserver`.
+
+use automerge::hydrate::Value;
+use axum::{Json, Router};
+use faktory::Job;
+use futures::lock::Mutex;
+use samod::{DocumentId, Repo};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{keys, monitoring, worker::JobQueue};
+
+/// How long to wait for each websocket connection to wind down at shutdown.
+const CONNECTION_STOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// The shared state of the repo server.
+#[derive(Clone)]
+pub struct RepoState {
+    repo: Repo,
+    running_connections: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+    jobs: JobQueue,
+}
+
+impl RepoState {
+    /// Create the repo server state. Documents submitted for compilation will
+    /// be sent to `jobs`.
+    pub fn new(repo: Repo, jobs: JobQueue) -> Self {
+        RepoState {
+            repo,
+            running_connections: Default::default(),
+            jobs,
+        }
+    }
+
+    /// Check whether the server is ready to handle requests. The samod repo has
+    /// necessarily finished loading by the time this state exists, so this
+    /// checks that compile jobs can be submitted.
+    pub async fn check_ready(&self) -> anyhow::Result<()> {
+        self.jobs.check_ready().await
+    }
+
+    /// Shut down the repo, flushing its storage, and wait for the websocket
+    /// sync connections to finish.
+    ///
+    /// This should be called after the HTTP server has stopped accepting new
+    /// connections.
+    pub async fn stop(self) {
+        self.repo.stop().await;
+
+        let connections = std::mem::take(&mut *self.running_connections.lock().await);
+
+        for handle in connections {
+            let abort = handle.abort_handle();
+
+            if tokio::time::timeout(CONNECTION_STOP_TIMEOUT, handle)
+                .await
+                .is_err()
+            {
+                tracing::warn!("websocket sync connection did not finish; aborting it");
+                abort.abort();
+            }
+        }
+    }
+}
+
+/// Create the router for the repo API. All routes live under `/ttpapi1/repo`.
+pub fn router(state: RepoState) -> Router {
+    Router::new()
+        .route(
+            "/ttpapi1/repo/submit",
+            axum::routing::post(post_submit_handler),
+        )
+        .route("/ttpapi1/repo/sync", axum::routing::get(websocket_handler))
+        .with_state(state)
+}
+
+async fn websocket_handler(
+    ws: axum::extract::ws::WebSocketUpgrade,
+    axum::extract::State(state): axum::extract::State<RepoState>,
+) -> axum::response::Response {
+    ws.on_upgrade(|socket| handle_socket(socket, state.repo, state.running_connections))
+}
+
+async fn handle_socket(
+    socket: axum::extract::ws::WebSocket,
+    repo: Repo,
+    running_connections: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+) {
+    tracing::info!("accepting websocket connection");
+    let driver = repo.accept_axum(socket);
+    monitoring::SYNC_CONNECTIONS.inc();
+    let handle = tokio::spawn(async {
+        let finished = driver.await;
+        monitoring::SYNC_CONNECTIONS.dec();
+        tracing::info!("websocket sync server connection finished: {finished:?}");
+    });
+
+    // Reap the tasks of any connections that have since finished, so that the
+    // list doesn't grow without bound.
+    let mut running_connections = running_connections.lock().await;
+    running_connections.retain(|h| !h.is_finished());
+    running_connections.push(handle);
+}
+
+#[derive(Deserialize)]
+struct PostSubmitRequest {
+    doc_id: String,
+}
+
+#[derive(Serialize)]
+struct PostSubmitResponse {
+    status: String,
+}
+
+/// `POST /submit`: submit proposed changes to a document. If accepted, they are
+/// sent off to be compiled.
+///
+/// Obviously right now we are not doing any authentication or checking or
+/// anything!!!!
+async fn post_submit_handler(
+    axum::extract::State(state): axum::extract::State<RepoState>,
+    Json(req): Json<PostSubmitRequest>,
+) -> Json<PostSubmitResponse> {
+    // Get the content! The document ID becomes part of the object keys of the
+    // outputs, so it has to be usable as one as well as parsing.
+
+    let doc_id: DocumentId = match keys::validate_key_segment(&req.doc_id)
+        .ok()
+        .and_then(|_| req.doc_id.parse().ok())
+    {
+        Some(i) => i,
+        None => {
+            return Json(PostSubmitResponse {
+                status: format!("illegal document ID {}", req.doc_id),
+            });
+        }
+    };
+
+    let doc_handle = match state.repo.find(doc_id).await {
+        Ok(Some(dh)) => dh,
+        Ok(None) => {
+            return Json(PostSubmitResponse {
+                status: format!("document {} not found", req.doc_id),
+            });
+        }
+        Err(_) => {
+            return Json(PostSubmitResponse {
+                status: "server shutting down".into(),
+            });
+        }
+    };
+
+    // XXX samod docs suggest running this as blocking
+    let maybe_content = doc_handle.with_document(|doc| {
+        // XXX set heads
+        let mut hdoc = doc.hydrate(None);
+        let cval = hdoc.as_map()?.get("content")?;
+
+        if let Value::Text(ctext) = cval {
+            Some(ctext.to_string())
+        } else {
+            None
+        }
+    });
+
+    let content = match maybe_content {
+        Some(c) => c,
+        None => {
+            return Json(PostSubmitResponse {
+                status: format!("malformatted document {}", req.doc_id),
+            });
+        }
+    };
+
+    // Send the job off to be compiled. Its ID is how the rest of the
+    // processing of this submission can be traced in the logs.
+
+    let job = Job::new("compile", vec![req.doc_id.clone(), content]);
+    let job_id = job.id().to_string();
+
+    state
+        .jobs
+        .enqueue(job)
+        .await
+        .expect("oh no job enqueue failed");
+    monitoring::SUBMISSIONS.inc();
+    tracing::info!(%job_id, doc_id = %req.doc_id, "queued compile job");
+
+    Json(PostSubmitResponse {
+        status: "ok".to_owned(),
+    })
+}
@@ -0,0 +1,231 @@
+//! Text analysis for full-text search over compiled entries.
+//!
+//! The search index itself is maintained by the nexus (see
+//! [`crate::nexus`]). This module holds the pieces that don't care about
+//! storage: extracting plain text from the HTML outputs of pass 2, breaking
+//! text into search terms, and generating snippets for search results.
+
+/// The maximum length of a search result snippet, in characters.
+const SNIPPET_CHARS: usize = 160;
+
+/// HTML elements whose contents are not text that should be searched.
+const SKIPPED_ELEMENTS: &[&str] = &["head", "script", "style", "template"];
+
+/// Extract the text content of an HTML document.
+///
+/// This is not a full HTML parser, but the outputs of the Tectonic HTML engine
+/// are well-formed enough that stripping tags is sufficient. Whitespace is
+/// collapsed, and the most common character references are decoded.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut rest = html;
+    let mut skipping: Option<String> = None;
+
+    while let Some(lt) = rest.find('<') {
+        if skipping.is_none() {
+            push_text(&mut text, &rest[..lt]);
+        }
+
+        rest = &rest[lt..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |i| &comment[i + 3..]);
+            continue;
+        }
+
+        let Some(gt) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+
+        let tag = &rest[1..gt];
+        rest = &rest[gt + 1..];
+
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(t) => (true, t),
+            None => (false, tag),
+        };
+
+        let name = tag
+            .split(|c: char| c.is_ascii_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        match &skipping {
+            Some(s) if closing && *s == name => skipping = None,
+            Some(_) => {}
+            None if !closing && SKIPPED_ELEMENTS.contains(&name.as_str()) => {
+                if !tag.ends_with('/') {
+                    skipping = Some(name);
+                }
+            }
+            // Tags separate words, except for inline formatting.
+            None => {
+                if !matches!(
+                    name.as_str(),
+                    "a" | "b" | "code" | "em" | "i" | "span" | "strong" | "sub" | "sup" | "tt"
+                ) {
+                    push_text(&mut text, " ");
+                }
+            }
+        }
+    }
+
+    if skipping.is_none() {
+        push_text(&mut text, rest);
+    }
+
+    text.truncate(text.trim_end().len());
+    text
+}
+
+/// Append some raw HTML text to `text`, decoding character references and
+/// collapsing whitespace.
+fn push_text(text: &mut String, mut raw: &str) {
+    while !raw.is_empty() {
+        let (c, len) = match raw.strip_prefix('&').and_then(decode_reference) {
+            Some((c, len)) => (c, len + 1),
+            None => {
+                let c = raw.chars().next().unwrap();
+                (c, c.len_utf8())
+            }
+        };
+
+        raw = &raw[len..];
+
+        if c.is_whitespace() {
+            if !text.is_empty() && !text.ends_with(' ') {
+                text.push(' ');
+            }
+        } else {
+            text.push(c);
+        }
+    }
+}
+
+/// Decode an HTML character reference, given the text following its `&`.
+/// Returns the character and the number of bytes consumed, including the
+/// trailing semicolon.
+pub fn decode_reference(s: &str) -> Option<(char, usize)> {
+    let semi = s.find(';').filter(|i| *i <= 10)?;
+    let name = &s[..semi];
+
+    let c = if let Some(num) = name.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        char::from_u32(code)?
+    } else {
+        match name {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            "nbsp" => ' ',
+            _ => return None,
+        }
+    };
+
+    Some((c, semi + 1))
+}
+
+/// Break text into search terms.
+///
+/// Terms are maximal runs of alphanumeric characters, lowercased. Everything
+/// else, including the backslashes of TeX control sequences, separates terms,
+/// so that a search for `dump` finds `\dump`.
+pub fn terms(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+}
+
+/// Generate a snippet of `text` showing the first occurrence of any of the
+/// given search terms, or the start of the text if none of them occur.
+pub fn snippet(text: &str, query_terms: &[String]) -> String {
+    let hit = text
+        .char_indices()
+        .filter(|(i, c)| {
+            c.is_alphanumeric()
+                && !text[..*i]
+                    .chars()
+                    .next_back()
+                    .is_some_and(char::is_alphanumeric)
+        })
+        .find(|(i, _)| {
+            let word = text[*i..]
+                .split(|c: char| !c.is_alphanumeric())
+                .next()
+                .unwrap_or_default();
+            query_terms.iter().any(|t| word.to_lowercase() == *t)
+        })
+        .map_or(0, |(i, _)| i);
+
+    // Start a little before the hit, at a word boundary, so that it has some
+    // context.
+
+    let mut start = text[..hit]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CHARS / 4)
+        .map_or(0, |(i, _)| i);
+
+    if start > 0 {
+        start = text[start..].find(' ').map_or(hit, |i| start + i + 1);
+    }
+
+    let body = &text[start..];
+    let end = body
+        .char_indices()
+        .nth(SNIPPET_CHARS)
+        .map_or(body.len(), |(i, _)| i);
+
+    let mut snippet = String::new();
+
+    if start > 0 {
+        snippet.push('…');
+    }
+
+    snippet.push_str(body[..end].trim());
+
+    if end < body.len() {
+        snippet.push('…');
+    }
+
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_1() {
+        let html = r#"<!DOCTYPE html><html><head><title>X</title><style>p { color: red; }</style></head>
+<body><h1>The <code>\dump</code> command</h1><!-- comment --><p>Ends&nbsp;a <em>format</em> &amp; stops.</p></body></html>"#;
+        assert_eq!(
+            html_to_text(html),
+            r"The \dump command Ends a format & stops."
+        );
+    }
+
+    #[test]
+    fn terms_1() {
+        let t: Vec<_> = terms(r"The \dump primitive, TeX’s dumper").collect();
+        assert_eq!(t, ["the", "dump", "primitive", "tex", "s", "dumper"]);
+    }
+
+    #[test]
+    fn snippet_1() {
+        let text = "word ".repeat(100) + "Needle in a haystack";
+        let s = snippet(&text, &["needle".to_owned()]);
+        assert!(s.starts_with("…word"));
+        assert!(s.ends_with("Needle in a haystack"));
+
+        let s = snippet("Short text.", &["missing".to_owned()]);
+        assert_eq!(s, "Short text.");
+    }
+}
@@ -0,0 +1,123 @@
+//! Running the HTTP servers, and shutting them down.
+
+use anyhow::Result;
+use axum::Router;
+use std::{future::IntoFuture, net::SocketAddr, time::Duration};
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::config::ServerConfig;
+
+/// How long a server waits for in-flight requests and open connections to
+/// finish once shutdown has been requested.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// A handle that resolves when the process has been asked to shut down by
+/// SIGINT or SIGTERM. Clones all observe the same request.
+#[derive(Clone, Debug)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Start listening for shutdown signals.
+    ///
+    /// This must be called from within a Tokio runtime.
+    pub fn listen() -> Self {
+        let (tx, rx) = watch::channel(false);
+
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            tracing::info!("shutdown requested");
+            let _ = tx.send(true);
+        });
+
+        Shutdown(rx)
+    }
+
+    /// Create a handle that resolves when the returned sender sends `true`
+    /// or is dropped, rather than on a signal. This allows shutdown to be
+    /// sequenced, for instance to keep a server up until other work has
+    /// finished.
+    pub fn manual() -> (watch::Sender<bool>, Self) {
+        let (tx, rx) = watch::channel(false);
+        (tx, Shutdown(rx))
+    }
+
+    /// Wait until shutdown has been requested.
+    pub async fn wait(mut self) {
+        // If the sender has gone away without signaling, something has gone
+        // wrong with the listener, and shutting down is the safest option.
+        let _ = self.0.wait_for(|requested| *requested).await;
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Serve `app` according to `config`, listening on `default_listen` if no
+/// address has been configured. If TLS has been configured, the server speaks
+/// HTTPS (and WSS) rather than plain HTTP.
+///
+/// Once `shutdown` resolves, the server stops accepting connections and this
+/// function returns after in-flight requests have been drained, or after a
+/// grace period if some connections, such as websockets, are slow to finish.
+pub async fn serve(
+    app: Router,
+    config: &ServerConfig,
+    default_listen: SocketAddr,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let addr = config.listen.unwrap_or(default_listen);
+
+    if let Some((cert, key)) = config.tls_paths()? {
+        let tls = axum_server::tls_rustls::RustlsConfig::from_pem_file(cert, key).await?;
+        let handle = axum_server::Handle::new();
+
+        tokio::spawn({
+            let handle = handle.clone();
+
+            async move {
+                shutdown.wait().await;
+                handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+            }
+        });
+
+        tracing::info!("listening on: https://{addr}/");
+        axum_server::bind_rustls(addr, tls)
+            .handle(handle)
+            .serve(app.into_make_service())
+            .await?;
+    } else {
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("listening on: http://{addr}/");
+        let server = axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown.clone().wait())
+            .into_future();
+        tokio::pin!(server);
+
+        tokio::select! {
+            outcome = &mut server => outcome?,
+
+            _ = shutdown.wait() => {
+                match tokio::time::timeout(SHUTDOWN_GRACE, server).await {
+                    Ok(outcome) => outcome?,
+                    Err(_) => tracing::warn!("connections did not finish in time; stopping anyway"),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,784 @@
+//! The compilation process that turns a pedia document into published HTML.
+//!
+//! This module is driven by the `ttpedia_compilerworker` binary, which pulls
+//! jobs from Faktory, or by `ttpedia_devserver`, which runs compilations
+//! in-process.
+
+use anyhow::{Context, Result, anyhow, bail};
+use faktory::Job;
+use futures::lock::Mutex;
+use std::{
+    collections::HashSet,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tectonic::{
+    config::PersistentConfig,
+    driver::{OutputFormat, PassSetting, ProcessingSessionBuilder},
+    status::termcolor::TermcolorStatusBackend,
+    unstable_opts::UnstableOptions,
+};
+use tectonic_bridge_core::{SecuritySettings, SecurityStance};
+use tectonic_bundles::{Bundle, detect_bundle, get_fallback_bundle_url};
+use tectonic_engine_spx2html::AssetSpecification;
+use tectonic_status_base::ChatterLevel;
+use tempfile::TempDir;
+use tokio::sync::mpsc;
+
+use crate::{
+    NexusPostAssetsUploadedRequest, NexusPostAssetsUploadedResponse,
+    NexusPostEntriesUploadedRequest, NexusPostEntriesUploadedResponse, NexusPostPass1Request,
+    NexusPostPass1Response, NexusUploadedEntry,
+    auth::ServiceKey,
+    html, keys,
+    metadata::{Metadatum, PediaTxtReader},
+    monitoring,
+    nexus::entry_name_for_output,
+    search,
+    serve::Shutdown,
+};
+
+const DEBUG: bool = false;
+
+/// Where compiled outputs are published.
+#[derive(Debug)]
+pub enum OutputStore {
+    /// Upload to an S3-compatible bucket storage service.
+    Bucket {
+        url: String,
+        username: String,
+        password: String,
+    },
+
+    /// Write into a local directory, with one subdirectory per bucket.
+    Directory(PathBuf),
+}
+
+/// Settings for the compilation process.
+#[derive(Debug)]
+pub struct WorkerConfig {
+    /// The pedia `defs` directory, containing the document class files.
+    pub defs_dir: PathBuf,
+
+    /// The base URL of the nexus API.
+    pub nexus_url: String,
+
+    /// The key for authenticating requests to the nexus.
+    pub nexus_key: ServiceKey,
+
+    /// Where outputs should be published.
+    pub store: OutputStore,
+
+    /// The name of the bucket holding compiled HTML.
+    pub html_bucket: String,
+
+    /// The name of the bucket holding shared assets.
+    pub shared_assets_bucket: String,
+
+    /// The TeX support bundle: a directory, a Zip or TTB file, or a URL. If
+    /// `None`, Tectonic's default bundle is used.
+    pub bundle: Option<String>,
+
+    /// The directory in which network bundles are cached, if not Tectonic's
+    /// default.
+    pub bundle_cache: Option<PathBuf>,
+
+    /// Whether to avoid the network and use only cached bundle files.
+    pub only_cached: bool,
+}
+
+impl WorkerConfig {
+    /// Open the configured TeX support bundle.
+    fn open_bundle(&self, persistent: &PersistentConfig) -> Result<Box<dyn Bundle>> {
+        // With nothing configured, let Tectonic pick the bundle as usual, which
+        // honors its own configuration file.
+        if self.bundle.is_none() && self.bundle_cache.is_none() {
+            return persistent
+                .default_bundle(self.only_cached)
+                .map_err(|e| anyhow!("failed to open the default bundle: {e}"));
+        }
+
+        let source = self
+            .bundle
+            .clone()
+            .unwrap_or_else(|| get_fallback_bundle_url(tectonic::FORMAT_SERIAL));
+
+        detect_bundle(source.clone(), self.only_cached, self.bundle_cache.clone())
+            .with_context(|| format!("failed to open bundle `{source}`"))?
+            .ok_or_else(|| anyhow!("`{source}` is not a recognized kind of bundle"))
+    }
+}
+
+/// A destination for compilation jobs.
+#[derive(Clone)]
+pub enum JobQueue {
+    /// Jobs are sent to a Faktory server.
+    Faktory(Arc<Mutex<faktory::Client>>),
+
+    /// Jobs are processed in this process by [`run_in_process`].
+    InProcess(mpsc::UnboundedSender<Job>),
+}
+
+impl JobQueue {
+    /// Submit a job for compilation.
+    pub async fn enqueue(&self, job: Job) -> Result<()> {
+        match self {
+            JobQueue::Faktory(client) => {
+                client.lock().await.enqueue(job).await?;
+            }
+
+            JobQueue::InProcess(sender) => {
+                sender
+                    .send(job)
+                    .map_err(|_| anyhow!("the in-process compile worker has exited"))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check whether jobs can currently be submitted.
+    pub async fn check_ready(&self) -> Result<()> {
+        match self {
+            JobQueue::Faktory(_) => {
+                // Use a fresh connection to avoid contending with submissions.
+                faktory::Client::connect().await?;
+            }
+
+            JobQueue::InProcess(sender) => {
+                if sender.is_closed() {
+                    bail!("the in-process compile worker has exited");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that documents can be compiled with the configured bundle, by
+/// compiling a trivial one with the `latex` format. If the format isn't cached
+/// yet, it's generated, which takes a while. This blocks, so it should be run
+/// with `spawn_blocking` from async code.
+pub fn check_tex_setup(config: &WorkerConfig) -> Result<()> {
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::default());
+    let persistent = PersistentConfig::open(false)
+        .map_err(|e| anyhow!("failed to load the Tectonic configuration: {e}"))?;
+    let format_cache = persistent
+        .format_cache_path()
+        .map_err(|e| anyhow!("failed to locate the format cache: {e}"))?;
+    let security = SecuritySettings::new(SecurityStance::DisableInsecures);
+
+    let mut sess = ProcessingSessionBuilder::new_with_security(security);
+    sess.primary_input_buffer(b"\\documentclass{article}\\begin{document}ok\\end{document}\n")
+        .tex_input_name("texput")
+        .bundle(config.open_bundle(&persistent)?)
+        .format_name("latex")
+        .format_cache_path(format_cache)
+        .output_format(OutputFormat::Xdv)
+        .do_not_write_output_files()
+        .pass(PassSetting::Default);
+
+    let mut sess = sess
+        .create(&mut status)
+        .map_err(|e| anyhow!("failed to set up the check compilation: {e}"))?;
+    sess.run(&mut status)
+        .map_err(|e| anyhow!("failed to compile with the `latex` format: {e}"))?;
+    Ok(())
+}
+
+/// Process compilation jobs in-process, one at a time, until the sending side
+/// of the channel is closed or shutdown is requested. A compilation that is
+/// underway when shutdown is requested is allowed to finish, but jobs that are
+/// still queued are dropped.
+pub async fn run_in_process(
+    config: Arc<WorkerConfig>,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+    shutdown: Shutdown,
+) {
+    loop {
+        let job = tokio::select! {
+            job = jobs.recv() => match job {
+                Some(j) => j,
+                None => break,
+            },
+
+            _ = shutdown.clone().wait() => break,
+        };
+
+        let job_id = job.id().to_string();
+
+        // Spawn so that a panic in the compilation only takes out the one job.
+        let outcome = tokio::spawn(compile(config.clone(), job)).await;
+
+        match outcome {
+            Ok(Ok(())) => tracing::info!(%job_id, "compile job finished"),
+            Ok(Err(e)) => tracing::error!(%job_id, "compile job failed: {e:#}"),
+            Err(e) => tracing::error!(%job_id, "compile job panicked: {e}"),
+        }
+    }
+
+    jobs.close();
+
+    while let Ok(job) = jobs.try_recv() {
+        tracing::warn!(job_id = %job.id(), "dropping queued compile job at shutdown");
+    }
+}
+
+/// Compile a TeX document in the Tectonopedia framework.
+///
+/// Everything logged during the compilation is associated with the job ID,
+/// which is also passed on to the nexus.
+#[tracing::instrument(skip_all, fields(job_id = %job.id(), doc_id = tracing::field::Empty))]
+pub async fn compile(config: Arc<WorkerConfig>, job: Job) -> Result<()> {
+    let mut state = CompileState::new(config, job);
+    let span = tracing::Span::current();
+    span.record("doc_id", state.doc_id());
+
+    // Both IDs become parts of object keys, so check them before doing any
+    // work.
+    keys::validate_key_segment(state.doc_id()).context("invalid document ID")?;
+    keys::validate_key_segment(&state.job.id().to_string()).context("invalid job ID")?;
+
+    // Compilation pass 1 - blocking
+    let pass_span = span.clone();
+    let (req, mut state) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let _entered = pass_span.enter();
+        let _timer = monitoring::compile_duration("1").start_timer();
+        let req = state.pass1()?;
+        Ok((req, state))
+    })
+    .await
+    .expect("join")?;
+
+    // Submit to nexus and process results
+    let resp = state.nexus1(req).await?;
+    let preserve_assets = resp.preserve_assets;
+
+    // Compilation pass 2.
+    let (out_dir, state) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let _entered = span.enter();
+        let _timer = monitoring::compile_duration("2").start_timer();
+        let out_dir = state.pass2(resp)?;
+        Ok((out_dir, state))
+    })
+    .await
+    .expect("join")?;
+
+    // upload to bucket
+    state.upload_to_bucket(out_dir, preserve_assets).await?;
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct CompileState {
+    config: Arc<WorkerConfig>,
+    job: Job,
+
+    /// The outputs declared in the `pedia.txt` metadata from pass 1.
+    outputs: Vec<String>,
+
+    /// The paths of the shared assets in the output tree, as specified by the
+    /// nexus for pass 2.
+    asset_paths: HashSet<String>,
+}
+
+impl CompileState {
+    fn new(config: Arc<WorkerConfig>, job: Job) -> Self {
+        CompileState {
+            config,
+            job,
+            outputs: Vec::new(),
+            asset_paths: HashSet::new(),
+        }
+    }
+
+    fn doc_id(&self) -> &str {
+        self.job.args()[0].as_str().unwrap()
+    }
+
+    fn content(&self) -> &str {
+        self.job.args()[1].as_str().unwrap()
+    }
+}
+
+impl CompileState {
+    /// First compilation pass.
+    fn pass1(&mut self) -> Result<NexusPostPass1Request> {
+        let mut status = TermcolorStatusBackend::new(ChatterLevel::default());
+        let config: PersistentConfig = PersistentConfig::open(false).expect("config");
+        let bundle = self.config.open_bundle(&config)?;
+        let security = SecuritySettings::new(SecurityStance::MaybeAllowInsecures);
+
+        let mut cls = self.config.defs_dir.clone();
+        cls.push("cls");
+        let unstables = UnstableOptions {
+            extra_search_paths: vec![cls],
+            ..UnstableOptions::default()
+        };
+
+        let input = format!(
+            "\\newif\\ifpassone \
+            \\passonetrue \
+            \\input{{preamble}}
+            {}
+            \\input{{postamble}}\n",
+            self.content(),
+        );
+
+        let mut sess = ProcessingSessionBuilder::new_with_security(security);
+        sess.primary_input_buffer(input.as_bytes())
+            .tex_input_name("texput")
+            .build_date(std::time::SystemTime::now())
+            .bundle(bundle)
+            .format_name("latex")
+            .output_format(OutputFormat::Html)
+            .do_not_write_output_files()
+            .filesystem_root(&self.config.defs_dir)
+            .unstables(unstables)
+            .format_cache_path(config.format_cache_path().expect("cachepath"))
+            .html_emit_files(false)
+            .html_assets_spec_path("assets.json")
+            .pass(PassSetting::Default);
+
+        if DEBUG {
+            sess.print_stdout(true);
+        }
+
+        let mut sess = sess.create(&mut status).expect("create");
+
+        // Print more details in the error case here?
+        sess.run(&mut status).expect("run!");
+
+        // Gather the metadata and report them to the Nexus server.
+
+        let mut files = sess.into_file_data();
+
+        let assets = files
+            .remove("assets.json")
+            .ok_or_else(|| anyhow!("no `assets.json` file output"))?;
+        let assets = String::from_utf8(assets.data).context("`assets.json` not UTF8")?;
+
+        let links = files
+            .remove("pedia.txt")
+            .ok_or_else(|| anyhow!("no `pedia.txt` file output"))?;
+        let links = String::from_utf8(links.data).context("`pedia.txt` not UTF8")?;
+
+        // Bad records are reported by the nexus, so we can skip them here.
+        self.outputs = PediaTxtReader::new(links.as_bytes())
+            .filter_map(|r| match r.ok()?.1.as_metadatum() {
+                Metadatum::Output(o) => Some(o.to_owned()),
+                _ => None,
+            })
+            .collect();
+
+        Ok(NexusPostPass1Request {
+            doc_id: self.doc_id().to_owned(),
+            job_id: self.job.id().to_string(),
+            assets_json: assets,
+            pedia_txt: links,
+        })
+    }
+
+    async fn nexus1(&mut self, req: NexusPostPass1Request) -> Result<NexusPostPass1Response> {
+        let client = reqwest::Client::new();
+        let resp = self
+            .config
+            .nexus_key
+            .post_json(&client, &format!("{}/pass1", self.config.nexus_url), &req)?
+            .send()
+            .await
+            .context("HTTP pass1 to nexus didnt send")?
+            .error_for_status()
+            .context("HTTP pass1 to nexus failed")?;
+        let payload = resp
+            .json::<NexusPostPass1Response>()
+            .await
+            .context("HTTP pass1 resp json")?;
+
+        for e in &payload.metadata_errors {
+            tracing::warn!("nexus rejected metadata: {e}");
+        }
+
+        Ok(payload)
+    }
+
+    /// Second compilation pass.
+    ///
+    /// Note: need to return the TempDir so as not to delete it!
+    fn pass2(&mut self, resp: NexusPostPass1Response) -> Result<TempDir> {
+        let mut status = TermcolorStatusBackend::new(ChatterLevel::default());
+        let config: PersistentConfig = PersistentConfig::open(false).expect("config");
+        let bundle = self.config.open_bundle(&config)?;
+        let security = SecuritySettings::new(SecurityStance::MaybeAllowInsecures);
+
+        let mut assets = AssetSpecification::default();
+        assets
+            .add_from_saved(Cursor::new(resp.assets_json.as_bytes()))
+            .expect("add assets");
+        self.asset_paths = assets.output_paths().map(|p| p.into_owned()).collect();
+
+        let mut cls = self.config.defs_dir.clone();
+        cls.push("cls");
+        let unstables = UnstableOptions {
+            extra_search_paths: vec![cls],
+            ..UnstableOptions::default()
+        };
+
+        let out_dir = TempDir::new().context("make tempdir")?;
+
+        let input = format!(
+            "\\newif\\ifpassone \
+            \\passonefalse \
+            \\input{{preamble}}
+            {}
+            {}
+            \\input{{postamble}}\n",
+            resp.resolved_reference_tex,
+            self.content(),
+        );
+
+        let mut sess = ProcessingSessionBuilder::new_with_security(security);
+        sess.primary_input_buffer(input.as_bytes())
+            .tex_input_name("texput")
+            .build_date(std::time::SystemTime::now())
+            .bundle(bundle)
+            .format_name("latex")
+            .output_format(OutputFormat::Html)
+            .html_precomputed_assets(assets)
+            .filesystem_root(&self.config.defs_dir)
+            .unstables(unstables)
+            .format_cache_path(config.format_cache_path().expect("cachepath"))
+            .output_dir(&out_dir)
+            .html_emit_files(true)
+            .html_emit_assets(resp.preserve_assets.is_some())
+            .pass(PassSetting::Default);
+
+        if DEBUG {
+            sess.print_stdout(true);
+        }
+
+        let mut sess = sess.create(&mut status).expect("create");
+
+        // Print more details in the error case here?
+        sess.run(&mut status).expect("run!");
+
+        // Gather results ...
+
+        tracing::info!("pass 2 done");
+        let mut files = sess.into_file_data();
+
+        for (fname, finfo) in files.drain() {
+            tracing::debug!("- memfile: {fname}: {}", finfo.data.len());
+        }
+
+        Ok(out_dir)
+    }
+
+    async fn upload_to_bucket(
+        &self,
+        out_dir: TempDir,
+        preserve_assets: Option<usize>,
+    ) -> Result<()> {
+        let store = StoreClient::new(&self.config.store)?;
+
+        // Upload the other files in the output tree that we know how to serve.
+        // Shared assets go to their bucket, if we've been asked to update it.
+        // Anything else is a resource of the pages, such as an image, and is
+        // published under the document's prefix by its output path, like the
+        // non-entry pages, so that relative links from those pages reach it.
+
+        for rel_path in walk_files(out_dir.path()).await? {
+            let Some(rel_str) = rel_path.to_str() else {
+                continue;
+            };
+
+            if let Err(e) = keys::validate_key_path(rel_str) {
+                tracing::warn!("not uploading file: {e:#}");
+                continue;
+            }
+
+            if self.outputs.iter().any(|o| o == rel_str) {
+                continue;
+            }
+
+            let content_type = match output_content_type(&rel_path) {
+                None | Some("text/html") => {
+                    tracing::debug!("not uploading undeclared output file `{rel_str}`");
+                    continue;
+                }
+                Some(t) => t,
+            };
+
+            let (bucket, object) = if self.asset_paths.contains(rel_str) {
+                if preserve_assets.is_none() {
+                    continue;
+                }
+
+                (
+                    &self.config.shared_assets_bucket,
+                    format!("{}/{rel_str}", self.job.id()),
+                )
+            } else {
+                (
+                    &self.config.html_bucket,
+                    format!("{}/{rel_str}", self.doc_id()),
+                )
+            };
+
+            store
+                .put(
+                    bucket,
+                    &object,
+                    &out_dir.path().join(&rel_path),
+                    content_type,
+                )
+                .await?;
+        }
+
+        // If that all worked, and we're preserving our assets, notify the nexus server to update
+        // its knowledge of the shared assets.
+
+        if let Some(seq_num) = preserve_assets {
+            let req = NexusPostAssetsUploadedRequest {
+                seq_num,
+                bucket_key: self.job.id().to_string(),
+            };
+
+            tracing::info!("notifying uploaded: {:?}", req);
+
+            let client = reqwest::Client::new();
+            let resp = self
+                .config
+                .nexus_key
+                .post_json(
+                    &client,
+                    &format!("{}/assets_uploaded", self.config.nexus_url),
+                    &req,
+                )?
+                .send()
+                .await
+                .context("HTTP assets-upload to nexus didnt send")?
+                .error_for_status()
+                .context("HTTP assets-upload to nexus failed")?;
+
+            // response is vacuous
+            resp.json::<NexusPostAssetsUploadedResponse>()
+                .await
+                .context("HTTP assets-upload resp json")?;
+        }
+
+        // If the shared assets are sufficiently up-to-date, we can upload the
+        // actual pages. The `\output` records of the metadata are the
+        // authoritative list of them.
+
+        let mut entries = Vec::new();
+
+        for output in &self.outputs {
+            let rel_path = Path::new(output);
+
+            if let Err(e) = keys::validate_key_path(output) {
+                tracing::warn!("not uploading output: {e:#}");
+                continue;
+            }
+
+            let path = out_dir.path().join(rel_path);
+
+            if !tokio::fs::try_exists(&path).await? {
+                tracing::warn!("declared output `{output}` was not created");
+                continue;
+            }
+
+            // Entry pages are published by their names alone; everything else
+            // keeps its path.
+
+            let entry_name = entry_name_for_output(output);
+            let object = match entry_name {
+                Some(name) => format!("{}/{name}.html", self.doc_id()),
+                None => format!("{}/{output}", self.doc_id()),
+            };
+            let content_type = output_content_type(rel_path).unwrap_or("application/octet-stream");
+
+            // The HTML is derived from user-editable content, so it has to be
+            // cleaned up before it goes anywhere public.
+
+            let html = if content_type == "text/html" {
+                let raw = tokio::fs::read_to_string(&path)
+                    .await
+                    .with_context(|| format!("reading `{}`", path.display()))?;
+                let clean = html::postprocess(&raw);
+                tokio::fs::write(&path, &clean)
+                    .await
+                    .with_context(|| format!("rewriting `{}`", path.display()))?;
+                Some(clean)
+            } else {
+                None
+            };
+
+            store
+                .put(&self.config.html_bucket, &object, &path, content_type)
+                .await?;
+
+            if let (Some(name), Some(html)) = (entry_name, html) {
+                entries.push(NexusUploadedEntry {
+                    name: name.to_owned(),
+                    text: search::html_to_text(&html),
+                });
+            }
+        }
+
+        // Finally, let the nexus index the text of the entries for searching.
+
+        let req = NexusPostEntriesUploadedRequest {
+            doc_id: self.doc_id().to_owned(),
+            job_id: self.job.id().to_string(),
+            entries,
+        };
+
+        let client = reqwest::Client::new();
+        let resp = self
+            .config
+            .nexus_key
+            .post_json(
+                &client,
+                &format!("{}/entries_uploaded", self.config.nexus_url),
+                &req,
+            )?
+            .send()
+            .await
+            .context("HTTP entries-upload to nexus didnt send")?
+            .error_for_status()
+            .context("HTTP entries-upload to nexus failed")?;
+
+        // response is vacuous
+        resp.json::<NexusPostEntriesUploadedResponse>()
+            .await
+            .context("HTTP entries-upload resp json")?;
+
+        Ok(())
+    }
+}
+
+/// List all of the files under `root`, recursively, as paths relative to it.
+async fn walk_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+
+    while let Some(rel_dir) = dirs.pop() {
+        let mut dir = tokio::fs::read_dir(root.join(&rel_dir))
+            .await
+            .context("readdir")?;
+
+        while let Some(entry) = dir.next_entry().await.context("readdirent")? {
+            let rel_path = rel_dir.join(entry.file_name());
+
+            if entry.file_type().await?.is_dir() {
+                dirs.push(rel_path);
+            } else {
+                files.push(rel_path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Get the content type with which an output file should be published, based
+/// on its extension. Returns `None` for files that we don't know how to serve.
+fn output_content_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+
+    Some(match ext.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "otf" => "font/otf",
+        "ttf" => "font/ttf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "pdf" => "application/pdf",
+        _ => return None,
+    })
+}
+
+/// A connection to an [`OutputStore`].
+enum StoreClient {
+    Bucket(minio::s3::client::Client),
+    Directory(PathBuf),
+}
+
+impl StoreClient {
+    fn new(store: &OutputStore) -> Result<Self> {
+        match store {
+            OutputStore::Bucket {
+                url,
+                username,
+                password,
+            } => {
+                let base_url: minio::s3::http::BaseUrl = url.parse()?;
+                let provider = minio::s3::creds::StaticProvider::new(username, password, None);
+                let client = minio::s3::client::ClientBuilder::new(base_url)
+                    .provider(Some(Box::new(provider)))
+                    .app_info(Some(("compilerworker".to_owned(), "0".to_owned())))
+                    .build()?;
+                Ok(StoreClient::Bucket(client))
+            }
+
+            OutputStore::Directory(root) => Ok(StoreClient::Directory(root.clone())),
+        }
+    }
+
+    /// Publish the file at `path` as `object` in the named bucket.
+    async fn put(&self, bucket: &str, object: &str, path: &Path, content_type: &str) -> Result<()> {
+        // Callers should have checked the pieces of the key already, but
+        // this is the last line of defense against clobbering other objects.
+        keys::validate_key_path(object)?;
+
+        let size = tokio::fs::metadata(path).await?.len();
+
+        match self {
+            StoreClient::Bucket(client) => {
+                let content: minio::s3::builders::ObjectContent = path.into();
+
+                let resp = client
+                    .put_object_content(bucket, object, content)
+                    .content_type(content_type.to_owned())
+                    .send()
+                    .await?;
+                tracing::info!(
+                    bucket,
+                    object = %resp.object,
+                    etag = %resp.etag,
+                    "uploaded object"
+                );
+            }
+
+            StoreClient::Directory(root) => {
+                let mut dest = root.join(bucket);
+                dest.push(object);
+
+                if let Some(parent) = dest.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                tokio::fs::copy(path, &dest)
+                    .await
+                    .with_context(|| format!("failed to copy output to `{}`", dest.display()))?;
+                tracing::info!(bucket, object, "wrote object");
+            }
+        }
+
+        monitoring::UPLOAD_BYTES
+            .with_label_values(&[bucket])
+            .inc_by(size);
+
+        Ok(())
+    }
+}
@@ -5,6 +5,9 @@ interface EntryInfo {
   doc_id: DocumentId,
   output_name: string,
   title: string,
+  name: string,
+  summary: string | null,
+  categories: string[],
 }
 
 export const useEntryInfo = async (name: string): Promise<Ref<EntryInfo>> => {
@@ -0,0 +1,7 @@
+export default defineEventHandler(async (event) => {
+    const config = useRuntimeConfig();
+    const indexName = getRouterParam(event, "indexName");
+    const entryName = getRouterParam(event, "entryName");
+    const url = `${config.internalNexusUrl}/backlinks/${indexName}/${entryName}`;
+    return await $fetch(url);
+});
@@ -0,0 +1,5 @@
+export default defineEventHandler(async () => {
+    const config = useRuntimeConfig();
+    const url = `${config.internalNexusUrl}/bibliography`;
+    return await $fetch(url);
+});
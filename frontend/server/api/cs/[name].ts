@@ -0,0 +1,6 @@
+export default defineEventHandler(async (event) => {
+    const config = useRuntimeConfig();
+    const name = getRouterParam(event, "name");
+    const url = `${config.internalNexusUrl}/cs/${encodeURIComponent(name ?? "")}`;
+    return await $fetch(url);
+});
@@ -0,0 +1,6 @@
+export default defineEventHandler(async (event) => {
+    const config = useRuntimeConfig();
+    const indexName = getRouterParam(event, "indexName");
+    const url = `${config.internalNexusUrl}/index/${indexName}`;
+    return await $fetch(url, { query: getQuery(event) });
+});
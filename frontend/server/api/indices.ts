@@ -0,0 +1,5 @@
+export default defineEventHandler(async () => {
+    const config = useRuntimeConfig();
+    const url = `${config.internalNexusUrl}/indices`;
+    return await $fetch(url);
+});
@@ -0,0 +1,5 @@
+export default defineEventHandler(async (event) => {
+    const config = useRuntimeConfig();
+    const url = `${config.internalNexusUrl}/search`;
+    return await $fetch(url, { query: getQuery(event) });
+});
@@ -0,0 +1,6 @@
+export default defineEventHandler(async (event) => {
+    const config = useRuntimeConfig();
+    const entryName = getRouterParam(event, "entryName");
+    const url = `${config.internalNexusUrl}/toc/${entryName}`;
+    return await $fetch(url);
+});